@@ -0,0 +1,574 @@
+use crate::tools::Tool;
+use crate::{InsertMode, Material, Simulation, EXPLOSION_RADIUS};
+use bevy::prelude::*;
+use std::fs;
+use strum::IntoEnumIterator;
+
+const BINDINGS_FILE: &str = "keys.conf";
+
+// Names used for keys in the bindings file and the help window
+const KEY_NAMES: [(&str, KeyCode); 67] = [
+    ("a", KeyCode::KeyA),
+    ("b", KeyCode::KeyB),
+    ("c", KeyCode::KeyC),
+    ("d", KeyCode::KeyD),
+    ("e", KeyCode::KeyE),
+    ("f", KeyCode::KeyF),
+    ("g", KeyCode::KeyG),
+    ("h", KeyCode::KeyH),
+    ("i", KeyCode::KeyI),
+    ("j", KeyCode::KeyJ),
+    ("k", KeyCode::KeyK),
+    ("l", KeyCode::KeyL),
+    ("m", KeyCode::KeyM),
+    ("n", KeyCode::KeyN),
+    ("o", KeyCode::KeyO),
+    ("p", KeyCode::KeyP),
+    ("q", KeyCode::KeyQ),
+    ("r", KeyCode::KeyR),
+    ("s", KeyCode::KeyS),
+    ("t", KeyCode::KeyT),
+    ("u", KeyCode::KeyU),
+    ("v", KeyCode::KeyV),
+    ("w", KeyCode::KeyW),
+    ("x", KeyCode::KeyX),
+    ("y", KeyCode::KeyY),
+    ("z", KeyCode::KeyZ),
+    ("0", KeyCode::Digit0),
+    ("1", KeyCode::Digit1),
+    ("2", KeyCode::Digit2),
+    ("3", KeyCode::Digit3),
+    ("4", KeyCode::Digit4),
+    ("5", KeyCode::Digit5),
+    ("6", KeyCode::Digit6),
+    ("7", KeyCode::Digit7),
+    ("8", KeyCode::Digit8),
+    ("9", KeyCode::Digit9),
+    ("F1", KeyCode::F1),
+    ("F2", KeyCode::F2),
+    ("F3", KeyCode::F3),
+    ("F4", KeyCode::F4),
+    ("F5", KeyCode::F5),
+    ("F6", KeyCode::F6),
+    ("F7", KeyCode::F7),
+    ("F8", KeyCode::F8),
+    ("F9", KeyCode::F9),
+    ("F10", KeyCode::F10),
+    ("F11", KeyCode::F11),
+    ("F12", KeyCode::F12),
+    ("Space", KeyCode::Space),
+    ("Enter", KeyCode::Enter),
+    ("Tab", KeyCode::Tab),
+    ("Escape", KeyCode::Escape),
+    ("Backspace", KeyCode::Backspace),
+    ("Delete", KeyCode::Delete),
+    ("Home", KeyCode::Home),
+    ("Left", KeyCode::ArrowLeft),
+    ("Right", KeyCode::ArrowRight),
+    ("Up", KeyCode::ArrowUp),
+    ("Down", KeyCode::ArrowDown),
+    ("[", KeyCode::BracketLeft),
+    ("]", KeyCode::BracketRight),
+    (",", KeyCode::Comma),
+    (".", KeyCode::Period),
+    ("-", KeyCode::Minus),
+    ("=", KeyCode::Equal),
+    ("`", KeyCode::Backquote),
+    ("/", KeyCode::Slash),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Action {
+    SelectMaterial(Material),
+    SelectSource(Material),
+    SelectSink(Material),
+    MixSource(Material),
+    FillAll,
+    FillMixture,
+    ResetRandom,
+    Flip,
+    Explode,
+    CycleBoundaries,
+    Undo,
+    Redo,
+    Copy,
+    Cut,
+    Paste,
+    RotatePaste,
+    MirrorPaste,
+    Cancel,
+    SaveStamp,
+    NextStamp,
+    SetTool(Tool),
+    CycleBrushShape,
+    CycleSourceShape,
+    CycleSourceDirection,
+    ToggleSourceFillEmpty,
+    ToggleSourceSchedule,
+    ToggleSourceQuantity,
+    SelectSourceAtCursor,
+    DeleteSource,
+    FasterSource,
+    SlowerSource,
+    ClearSources,
+    ClearSinks,
+    ToggleSourcesPaused,
+    // Moves the selected source, or the view when there isn't one
+    Move(i32, i32),
+    ResetView,
+    TogglePaused,
+    Step,
+    InsertRate(usize),
+    SmallerPixels,
+    BiggerPixels,
+    ToggleMaterialView,
+    TogglePanel,
+    ToggleHelp,
+}
+
+const TOOLS: [(Tool, &str); 8] = [
+    (Tool::Freehand, "freehand"),
+    (Tool::Line, "line"),
+    (Tool::Rectangle { filled: false }, "rectangle"),
+    (Tool::Rectangle { filled: true }, "filled-rectangle"),
+    (Tool::Ellipse { filled: false }, "ellipse"),
+    (Tool::Ellipse { filled: true }, "filled-ellipse"),
+    (Tool::FloodFill, "flood-fill"),
+    (Tool::Select, "select"),
+];
+
+const MOVES: [((i32, i32), &str); 4] = [
+    ((-1, 0), "left"),
+    ((1, 0), "right"),
+    ((0, -1), "up"),
+    ((0, 1), "down"),
+];
+
+impl Action {
+    // The name used for the action in the bindings file
+    pub(crate) fn name(&self) -> String {
+        let material_name = |material: &Material| format!("{material:?}").to_lowercase();
+        match self {
+            Action::SelectMaterial(material) => format!("material.{}", material_name(material)),
+            Action::SelectSource(material) => format!("source.{}", material_name(material)),
+            Action::SelectSink(material) => format!("sink.{}", material_name(material)),
+            Action::MixSource(material) => format!("mix.{}", material_name(material)),
+            Action::SetTool(tool) => {
+                let (_, name) = TOOLS.iter().find(|(t, _)| t == tool).unwrap();
+                format!("tool.{name}")
+            }
+            Action::Move(dx, dy) => {
+                let (_, name) = MOVES.iter().find(|(d, _)| *d == (*dx, *dy)).unwrap();
+                format!("move.{name}")
+            }
+            Action::InsertRate(rate) => format!("insert-rate.{rate}"),
+            Action::FillAll => "fill-all".into(),
+            Action::FillMixture => "fill-mixture".into(),
+            Action::ResetRandom => "randomise".into(),
+            Action::Flip => "flip".into(),
+            Action::Explode => "explode".into(),
+            Action::CycleBoundaries => "cycle-edges".into(),
+            Action::Undo => "undo".into(),
+            Action::Redo => "redo".into(),
+            Action::Copy => "copy".into(),
+            Action::Cut => "cut".into(),
+            Action::Paste => "paste".into(),
+            Action::RotatePaste => "rotate-paste".into(),
+            Action::MirrorPaste => "mirror-paste".into(),
+            Action::Cancel => "cancel".into(),
+            Action::SaveStamp => "save-stamp".into(),
+            Action::NextStamp => "next-stamp".into(),
+            Action::CycleBrushShape => "brush-shape".into(),
+            Action::CycleSourceShape => "source-shape".into(),
+            Action::CycleSourceDirection => "source-direction".into(),
+            Action::ToggleSourceFillEmpty => "source-fill-empty".into(),
+            Action::ToggleSourceSchedule => "source-schedule".into(),
+            Action::ToggleSourceQuantity => "source-quantity".into(),
+            Action::SelectSourceAtCursor => "select-source".into(),
+            Action::DeleteSource => "delete-source".into(),
+            Action::FasterSource => "faster-source".into(),
+            Action::SlowerSource => "slower-source".into(),
+            Action::ClearSources => "clear-sources".into(),
+            Action::ClearSinks => "clear-sinks".into(),
+            Action::ToggleSourcesPaused => "pause-sources".into(),
+            Action::ResetView => "reset-view".into(),
+            Action::TogglePaused => "pause".into(),
+            Action::Step => "step".into(),
+            Action::SmallerPixels => "smaller-pixels".into(),
+            Action::BiggerPixels => "bigger-pixels".into(),
+            Action::ToggleMaterialView => "material-view".into(),
+            Action::TogglePanel => "panel".into(),
+            Action::ToggleHelp => "help".into(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct Binding {
+    key: KeyCode,
+    ctrl: bool,
+    shift: bool,
+    alt: bool,
+}
+
+impl Binding {
+    fn parse(text: &str) -> Result<Binding, String> {
+        let mut parts: Vec<&str> = text.split('+').map(|part| part.trim()).collect();
+        let name = parts.pop().unwrap_or_default();
+        let code = KEY_NAMES
+            .iter()
+            .find(|(key_name, _)| key_name.eq_ignore_ascii_case(name))
+            .map(|(_, code)| *code)
+            .ok_or(format!("unknown key {name:?}"))?;
+
+        let mut binding = key(code);
+        for modifier in parts {
+            match modifier.to_lowercase().as_str() {
+                "ctrl" => binding.ctrl = true,
+                "shift" => binding.shift = true,
+                "alt" => binding.alt = true,
+                _ => return Err(format!("unknown modifier {modifier:?}")),
+            }
+        }
+        Ok(binding)
+    }
+
+    fn matches_modifiers(&self, keys: &ButtonInput<KeyCode>) -> bool {
+        self.ctrl == keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight])
+            && self.shift == keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight])
+            && self.alt == keys.any_pressed([KeyCode::AltLeft, KeyCode::AltRight])
+    }
+}
+
+impl std::fmt::Display for Binding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.ctrl {
+            write!(f, "Ctrl+")?;
+        }
+        if self.shift {
+            write!(f, "Shift+")?;
+        }
+        if self.alt {
+            write!(f, "Alt+")?;
+        }
+        let (name, _) = KEY_NAMES
+            .iter()
+            .find(|(_, code)| *code == self.key)
+            .unwrap();
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Resource)]
+pub(crate) struct KeyBindings {
+    pub(crate) bindings: Vec<(Action, Vec<Binding>)>,
+}
+
+fn key(key: KeyCode) -> Binding {
+    Binding {
+        key,
+        ctrl: false,
+        shift: false,
+        alt: false,
+    }
+}
+
+fn ctrl(key: KeyCode) -> Binding {
+    Binding {
+        ctrl: true,
+        ..self::key(key)
+    }
+}
+
+fn shift(key: KeyCode) -> Binding {
+    Binding {
+        shift: true,
+        ..self::key(key)
+    }
+}
+
+fn alt(key: KeyCode) -> Binding {
+    Binding {
+        alt: true,
+        ..self::key(key)
+    }
+}
+
+fn material_key(material: Material) -> KeyCode {
+    let name = material.code().to_string();
+    KEY_NAMES.iter().find(|(n, _)| *n == name).unwrap().1
+}
+
+// The layout the simulator has always had
+impl Default for KeyBindings {
+    fn default() -> Self {
+        let mut bindings = Vec::new();
+        for material in Material::iter() {
+            let code = material_key(material);
+            bindings.push((Action::SelectMaterial(material), vec![key(code)]));
+            bindings.push((Action::SelectSource(material), vec![shift(code)]));
+            bindings.push((Action::SelectSink(material), vec![ctrl(code)]));
+            bindings.push((Action::MixSource(material), vec![alt(code)]));
+        }
+        bindings.extend([
+            (Action::FillAll, vec![key(KeyCode::Space)]),
+            (Action::FillMixture, vec![shift(KeyCode::Space)]),
+            (Action::ResetRandom, vec![key(KeyCode::Enter)]),
+            (Action::Flip, vec![key(KeyCode::KeyU)]),
+            (Action::Explode, vec![key(KeyCode::KeyX)]),
+            (Action::CycleBoundaries, vec![key(KeyCode::KeyE)]),
+            (Action::Undo, vec![ctrl(KeyCode::KeyZ)]),
+            (
+                Action::Redo,
+                vec![
+                    Binding {
+                        shift: true,
+                        ..ctrl(KeyCode::KeyZ)
+                    },
+                    ctrl(KeyCode::KeyY),
+                ],
+            ),
+            (Action::Copy, vec![ctrl(KeyCode::KeyC)]),
+            (Action::Cut, vec![ctrl(KeyCode::KeyX)]),
+            (Action::Paste, vec![ctrl(KeyCode::KeyV)]),
+            (Action::RotatePaste, vec![key(KeyCode::Period)]),
+            (Action::MirrorPaste, vec![key(KeyCode::Comma)]),
+            (Action::Cancel, vec![key(KeyCode::Escape)]),
+            (Action::SaveStamp, vec![key(KeyCode::F9)]),
+            (Action::NextStamp, vec![key(KeyCode::F10)]),
+        ]);
+        let tool_keys = [
+            KeyCode::F1,
+            KeyCode::F2,
+            KeyCode::F3,
+            KeyCode::F4,
+            KeyCode::F5,
+            KeyCode::F6,
+            KeyCode::F7,
+            KeyCode::F8,
+        ];
+        for ((tool, _), code) in TOOLS.iter().zip(tool_keys) {
+            bindings.push((Action::SetTool(*tool), vec![key(code)]));
+        }
+        bindings.extend([
+            (Action::CycleBrushShape, vec![key(KeyCode::KeyH)]),
+            (Action::CycleSourceShape, vec![key(KeyCode::Tab)]),
+            (Action::CycleSourceDirection, vec![key(KeyCode::KeyV)]),
+            (Action::ToggleSourceFillEmpty, vec![key(KeyCode::KeyK)]),
+            (Action::ToggleSourceSchedule, vec![key(KeyCode::KeyJ)]),
+            (Action::ToggleSourceQuantity, vec![key(KeyCode::KeyQ)]),
+            (Action::SelectSourceAtCursor, vec![key(KeyCode::KeyI)]),
+            (
+                Action::DeleteSource,
+                vec![key(KeyCode::Delete), key(KeyCode::Backspace)],
+            ),
+            (Action::FasterSource, vec![key(KeyCode::Equal)]),
+            (Action::SlowerSource, vec![key(KeyCode::Minus)]),
+            (Action::ClearSources, vec![key(KeyCode::KeyC)]),
+            (Action::ClearSinks, vec![shift(KeyCode::KeyC)]),
+            (Action::ToggleSourcesPaused, vec![shift(KeyCode::KeyP)]),
+        ]);
+        let move_keys = [
+            KeyCode::ArrowLeft,
+            KeyCode::ArrowRight,
+            KeyCode::ArrowUp,
+            KeyCode::ArrowDown,
+        ];
+        for (((dx, dy), _), code) in MOVES.iter().zip(move_keys) {
+            bindings.push((Action::Move(*dx, *dy), vec![key(code)]));
+        }
+        bindings.extend([
+            (Action::ResetView, vec![key(KeyCode::Home)]),
+            (Action::TogglePaused, vec![key(KeyCode::KeyP)]),
+            (Action::Step, vec![key(KeyCode::KeyN)]),
+        ]);
+        for rate in 1..=9 {
+            let code = KEY_NAMES
+                .iter()
+                .find(|(n, _)| *n == rate.to_string())
+                .unwrap()
+                .1;
+            bindings.push((Action::InsertRate(rate), vec![key(code)]));
+        }
+        bindings.extend([
+            (Action::SmallerPixels, vec![key(KeyCode::BracketLeft)]),
+            (Action::BiggerPixels, vec![key(KeyCode::BracketRight)]),
+            (Action::ToggleMaterialView, vec![key(KeyCode::KeyM)]),
+            (Action::TogglePanel, vec![key(KeyCode::Backquote)]),
+            // ? is not on the Slash key everywhere, so F12 works whatever the keyboard layout
+            (
+                Action::ToggleHelp,
+                vec![key(KeyCode::F12), shift(KeyCode::Slash)],
+            ),
+        ]);
+        KeyBindings { bindings }
+    }
+}
+
+impl KeyBindings {
+    // The default layout with any changes from the bindings file
+    pub(crate) fn load() -> KeyBindings {
+        let mut bindings = KeyBindings::default();
+        let Ok(text) = fs::read_to_string(BINDINGS_FILE) else {
+            return bindings;
+        };
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Err(error) = bindings.rebind(line) {
+                println!("ERROR: {BINDINGS_FILE:?} line {}: {error}", number + 1);
+            }
+        }
+        bindings
+    }
+
+    // Replaces the keys for an action from a line like `undo = Ctrl+z, Ctrl+Shift+z`
+    fn rebind(&mut self, line: &str) -> Result<(), String> {
+        let (name, keys) = line.split_once('=').ok_or("expected action = keys")?;
+        let name = name.trim();
+        let keys = keys.trim();
+        let new_bindings = if keys == "none" {
+            vec![]
+        } else {
+            keys.split(',')
+                .map(Binding::parse)
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        let (_, bindings) = self
+            .bindings
+            .iter_mut()
+            .find(|(action, _)| action.name() == name)
+            .ok_or(format!("unknown action {name:?}"))?;
+        *bindings = new_bindings;
+        Ok(())
+    }
+
+    // Actions whose keys were pressed this frame
+    pub(crate) fn just_pressed(&self, keys: &ButtonInput<KeyCode>) -> Vec<Action> {
+        self.bindings
+            .iter()
+            .filter(|(_, bindings)| {
+                bindings.iter().any(|binding| {
+                    keys.just_pressed(binding.key) && binding.matches_modifiers(keys)
+                })
+            })
+            .map(|(action, _)| *action)
+            .collect()
+    }
+
+    // Actions whose keys are being held down
+    pub(crate) fn pressed(&self, keys: &ButtonInput<KeyCode>) -> Vec<Action> {
+        self.bindings
+            .iter()
+            .filter(|(_, bindings)| {
+                bindings
+                    .iter()
+                    .any(|binding| keys.pressed(binding.key) && binding.matches_modifiers(keys))
+            })
+            .map(|(action, _)| *action)
+            .collect()
+    }
+}
+
+impl Simulation {
+    pub(crate) fn perform(&mut self, action: Action) {
+        match action {
+            Action::SelectMaterial(material) => self.set_material(material, InsertMode::Material),
+            Action::SelectSource(material) => self.set_material(material, InsertMode::Source),
+            Action::SelectSink(material) => self.set_material(material, InsertMode::Sink),
+            Action::MixSource(material) => self.mix_source_material(material),
+            Action::FillAll => self.set_all(),
+            Action::FillMixture => match self.selection {
+                Some(_) => self.fill_selection_mixture(),
+                None => self.fill_all_mixture(),
+            },
+            Action::ResetRandom => self.reset_random(),
+            Action::Flip => self.flip(),
+            Action::Explode => {
+                if let Some((x, y)) = self.cursor {
+                    self.explode(x, y, EXPLOSION_RADIUS);
+                }
+            }
+            Action::CycleBoundaries => self.cycle_boundaries(),
+            Action::Undo => self.undo(),
+            Action::Redo => self.redo(),
+            Action::Copy => self.copy_selection(),
+            Action::Cut => self.cut_selection(),
+            Action::Paste => self.start_paste(),
+            Action::RotatePaste => self.rotate_clipboard(),
+            Action::MirrorPaste => self.mirror_clipboard(),
+            Action::Cancel => {
+                self.selection = None;
+                self.set_tool(Tool::Freehand);
+            }
+            Action::SaveStamp => self.save_stamp(),
+            Action::NextStamp => self.next_stamp(),
+            Action::SetTool(tool) => self.set_tool(tool),
+            Action::CycleBrushShape => self.cycle_brush_shape(),
+            Action::CycleSourceShape => self.cycle_source_shape(),
+            Action::CycleSourceDirection => self.cycle_source_direction(),
+            Action::ToggleSourceFillEmpty => self.toggle_source_fill_empty(),
+            Action::ToggleSourceSchedule => self.toggle_source_schedule(),
+            Action::ToggleSourceQuantity => self.toggle_source_quantity(),
+            Action::SelectSourceAtCursor => self.select_source_at_cursor(),
+            Action::DeleteSource => self.delete_selected_source(),
+            Action::FasterSource => self.change_selected_source_rate(1),
+            Action::SlowerSource => self.change_selected_source_rate(-1),
+            Action::ClearSources => self.clear_sources(),
+            Action::ClearSinks => self.clear_sinks(),
+            Action::ToggleSourcesPaused => self.toggle_sources_paused(),
+            // Panning the view happens while the key is held instead
+            Action::Move(dx, dy) => self.move_selected_source(dx, dy),
+            Action::ResetView => self.reset_view(),
+            Action::TogglePaused => self.toggle_paused(),
+            Action::Step => self.step(),
+            Action::InsertRate(rate) => self.set_insert_rate(rate),
+            Action::SmallerPixels => self.decrease_pixel_size(),
+            Action::BiggerPixels => self.increase_pixel_size(),
+            Action::ToggleMaterialView => self.toggle_show_materials(),
+            Action::TogglePanel => self.show_panel = !self.show_panel,
+            Action::ToggleHelp => self.show_help = !self.show_help,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bindings_parse_with_modifiers_in_any_case() {
+        let binding = Binding::parse("ctrl + Shift+Z").unwrap();
+        assert!(binding.ctrl && binding.shift && !binding.alt);
+        assert_eq!(binding.key, KeyCode::KeyZ);
+        assert_eq!(binding.to_string(), "Ctrl+Shift+z");
+        assert_eq!(Binding::parse("F12").unwrap().key, KeyCode::F12);
+    }
+
+    #[test]
+    fn bad_bindings_are_rejected() {
+        assert!(Binding::parse("Hyper+z").is_err());
+        assert!(Binding::parse("Ctrl+nothing").is_err());
+        assert!(Binding::parse("").is_err());
+    }
+
+    #[test]
+    fn rebinding_replaces_the_keys_for_an_action() {
+        let mut bindings = KeyBindings::default();
+        bindings.rebind("help = F11, Ctrl+h").unwrap();
+        bindings.rebind("panel = none").unwrap();
+        let keys = |action: Action| {
+            let (_, keys) = bindings
+                .bindings
+                .iter()
+                .find(|(a, _)| *a == action)
+                .unwrap();
+            keys.iter().map(|key| key.to_string()).collect::<Vec<_>>()
+        };
+        assert_eq!(keys(Action::ToggleHelp), ["F11", "Ctrl+h"]);
+        assert!(keys(Action::TogglePanel).is_empty());
+        assert!(bindings.rebind("fly = x").is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use crate::properties::Properties;
+use crate::{Material, Particle, Simulation};
+use strum_macros::EnumIter;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Boundary {
+    // Nothing passes through
+    Wall,
+    // Leaving one side comes back in on the opposite side
+    Wrap,
+    // Particles that leave are deleted and counted in `Simulation::outflow`
+    Open,
+    // A wall that keeps the cells along it topped up with a material
+    Inflow(Material),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, EnumIter)]
+pub(crate) enum Edge {
+    Top,
+    Bottom,
+    Left,
+    Right,
+}
+
+pub(crate) struct Boundaries {
+    pub(crate) top: Boundary,
+    pub(crate) bottom: Boundary,
+    pub(crate) left: Boundary,
+    pub(crate) right: Boundary,
+}
+
+impl Boundaries {
+    pub(crate) fn walls() -> Self {
+        Boundaries {
+            top: Boundary::Wall,
+            bottom: Boundary::Wall,
+            left: Boundary::Wall,
+            right: Boundary::Wall,
+        }
+    }
+
+    pub(crate) fn get(&self, edge: Edge) -> Boundary {
+        match edge {
+            Edge::Top => self.top,
+            Edge::Bottom => self.bottom,
+            Edge::Left => self.left,
+            Edge::Right => self.right,
+        }
+    }
+
+    fn get_mut(&mut self, edge: Edge) -> &mut Boundary {
+        match edge {
+            Edge::Top => &mut self.top,
+            Edge::Bottom => &mut self.bottom,
+            Edge::Left => &mut self.left,
+            Edge::Right => &mut self.right,
+        }
+    }
+}
+
+fn opposite(edge: Edge) -> Edge {
+    match edge {
+        Edge::Top => Edge::Bottom,
+        Edge::Bottom => Edge::Top,
+        Edge::Left => Edge::Right,
+        Edge::Right => Edge::Left,
+    }
+}
+
+// Stands in for the cells beyond an open edge - Air that never runs out of room to move
+pub(crate) fn void_particle(properties: &Properties) -> Particle {
+    let mut particle = Particle::new(Material::Air, 0.0, properties);
+    particle.viscosity = f32::MAX;
+    particle
+}
+
+impl Simulation {
+    pub(crate) fn set_boundary(&mut self, edge: Edge, boundary: Boundary) {
+        // Wrapping only makes sense for both opposite edges together
+        if boundary == Boundary::Wrap {
+            *self.boundaries.get_mut(opposite(edge)) = Boundary::Wrap;
+        } else if self.boundaries.get(edge) == Boundary::Wrap {
+            *self.boundaries.get_mut(opposite(edge)) = Boundary::Wall;
+        }
+        *self.boundaries.get_mut(edge) = boundary;
+    }
+
+    // Steps through walls, wrap-around, a river, a waterfall and fully open edges
+    pub(crate) fn cycle_boundaries(&mut self) {
+        let inflow = Boundary::Inflow(self.material);
+        let (top, bottom, left, right) = match (
+            self.boundaries.top,
+            self.boundaries.bottom,
+            self.boundaries.left,
+            self.boundaries.right,
+        ) {
+            (Boundary::Wall, Boundary::Wall, Boundary::Wall, Boundary::Wall) => (
+                Boundary::Wrap,
+                Boundary::Wrap,
+                Boundary::Wrap,
+                Boundary::Wrap,
+            ),
+            (Boundary::Wrap, _, _, _) => (Boundary::Wall, Boundary::Wall, inflow, Boundary::Open),
+            (Boundary::Wall, Boundary::Wall, Boundary::Inflow(_), Boundary::Open) => {
+                (inflow, Boundary::Open, Boundary::Wall, Boundary::Wall)
+            }
+            (Boundary::Inflow(_), Boundary::Open, Boundary::Wall, Boundary::Wall) => (
+                Boundary::Open,
+                Boundary::Open,
+                Boundary::Open,
+                Boundary::Open,
+            ),
+            _ => (
+                Boundary::Wall,
+                Boundary::Wall,
+                Boundary::Wall,
+                Boundary::Wall,
+            ),
+        };
+        self.set_boundary(Edge::Top, top);
+        self.set_boundary(Edge::Bottom, bottom);
+        self.set_boundary(Edge::Left, left);
+        self.set_boundary(Edge::Right, right);
+    }
+
+    // The grid index of a cell, following wrapped edges round to the other side
+    pub(crate) fn index_at(&self, x: i32, y: i32) -> Option<usize> {
+        let width = self.width as i32;
+        let height = self.height as i32;
+        let x = match self.boundaries.left {
+            Boundary::Wrap => x.rem_euclid(width),
+            _ => x,
+        };
+        let y = match self.boundaries.top {
+            Boundary::Wrap => y.rem_euclid(height),
+            _ => y,
+        };
+        if x >= 0 && x < width && y >= 0 && y < height {
+            Some(y as usize * self.width + x as usize)
+        } else {
+            None
+        }
+    }
+
+    // The boundary crossed to reach a cell outside the grid
+    pub(crate) fn boundary_at(&self, x: i32, y: i32) -> Boundary {
+        if y < 0 {
+            self.boundaries.top
+        } else if y >= self.height as i32 {
+            self.boundaries.bottom
+        } else if x < 0 {
+            self.boundaries.left
+        } else {
+            self.boundaries.right
+        }
+    }
+
+    // Whether a cell sits against an edge that holds things in place. A wrapped floor still
+    // holds up solids, otherwise terrain in a wrapped world would fall round forever
+    pub(crate) fn against_wall(&self, idx: usize) -> bool {
+        let x = idx % self.width;
+        let y = idx / self.width;
+        let holds = |boundary: Boundary| matches!(boundary, Boundary::Wall | Boundary::Inflow(_));
+        (y == 0 && holds(self.boundaries.top))
+            || (y == self.height - 1
+                && (holds(self.boundaries.bottom) || self.boundaries.bottom == Boundary::Wrap))
+            || (x == 0 && holds(self.boundaries.left))
+            || (x == self.width - 1 && holds(self.boundaries.right))
+    }
+
+    pub(crate) fn remove_through_edge(&mut self, idx: usize) {
+        let material = self.grid[idx].material;
+        if material != Material::Air {
+            *self.outflow.entry(material).or_insert(0) += 1;
+            let alpha = self.grid[idx].alpha;
+            self.grid[idx] = Particle::new(Material::Air, alpha, &self.properties);
+        }
+    }
+
+    pub(crate) fn update_inflow(&mut self) {
+        for edge in [Edge::Top, Edge::Bottom, Edge::Left, Edge::Right] {
+            if let Boundary::Inflow(material) = self.boundaries.get(edge) {
+                let cells: Vec<usize> = match edge {
+                    Edge::Top => (0..self.width).collect(),
+                    Edge::Bottom => (0..self.width)
+                        .map(|x| (self.height - 1) * self.width + x)
+                        .collect(),
+                    Edge::Left => (0..self.height).map(|y| y * self.width).collect(),
+                    Edge::Right => (0..self.height)
+                        .map(|y| y * self.width + self.width - 1)
+                        .collect(),
+                };
+                for idx in cells {
+                    if self.grid[idx].material == Material::Air {
+                        self.grid[idx].set_material(material, &self.properties);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Boundary, Edge};
+    use crate::{Material, Simulation};
+    use rand::prelude::*;
+    use std::collections::HashMap;
+
+    fn update_cell(simulation: &mut Simulation, x: usize, y: usize) {
+        let idx = y * simulation.width + x;
+        let order_idx = simulation.order.iter().position(|&i| i == idx).unwrap();
+        simulation.update_tile(
+            order_idx,
+            &mut StdRng::seed_from_u64(0),
+            &mut HashMap::new(),
+        );
+    }
+
+    #[test]
+    fn particles_wrap_round_to_the_other_side() {
+        let mut simulation = Simulation::empty(3, 3);
+        simulation.set_boundary(Edge::Bottom, Boundary::Wrap);
+        assert_eq!(simulation.boundaries.top, Boundary::Wrap);
+        simulation.put(1, 2, Material::Sand);
+
+        update_cell(&mut simulation, 1, 2);
+        assert_eq!(simulation.grid[1].material, Material::Sand);
+        assert_eq!(simulation.grid[7].material, Material::Air);
+    }
+
+    #[test]
+    fn open_edges_remove_and_count_what_leaves() {
+        let mut simulation = Simulation::empty(3, 3);
+        simulation.set_boundary(Edge::Bottom, Boundary::Open);
+        simulation.put(1, 2, Material::Sand);
+
+        update_cell(&mut simulation, 1, 2);
+        assert_eq!(simulation.grid[7].material, Material::Air);
+        assert_eq!(simulation.outflow.get(&Material::Sand), Some(&1));
+    }
+
+    #[test]
+    fn inflow_tops_up_air_along_its_edge() {
+        let mut simulation = Simulation::empty(3, 3);
+        simulation.set_boundary(Edge::Left, Boundary::Inflow(Material::Water));
+        simulation.put(0, 1, Material::Rock);
+
+        simulation.update_inflow();
+        let left: Vec<Material> = (0..3).map(|y| simulation.grid[y * 3].material).collect();
+        assert_eq!(left, [Material::Water, Material::Rock, Material::Water]);
+        assert_eq!(simulation.grid[1].material, Material::Air);
+    }
+
+    #[test]
+    fn a_wrapped_floor_holds_up_solids() {
+        let mut simulation = Simulation::empty(3, 3);
+        for edge in [Edge::Top, Edge::Left] {
+            simulation.set_boundary(edge, Boundary::Wrap);
+        }
+        simulation.put(1, 2, Material::Rock);
+        simulation.put(1, 0, Material::Rock);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        simulation.update_support(&mut rng);
+        simulation.update_rigid_bodies(&mut rng);
+        assert_eq!(simulation.grid[7].material, Material::Rock);
+        assert_eq!(simulation.grid[1].material, Material::Rock);
+    }
+}
@@ -0,0 +1,152 @@
+use crate::tools::Tool;
+use crate::{line_points, Simulation};
+use rand::prelude::*;
+use std::collections::HashSet;
+
+pub(crate) const MAX_BRUSH_RADIUS: i32 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BrushShape {
+    Circle,
+    Square,
+    // Randomly scattered cells within a circle
+    Spray,
+}
+
+pub(crate) struct Brush {
+    pub(crate) radius: i32,
+    pub(crate) shape: BrushShape,
+    // Fraction of the cells a spray covers each frame
+    pub(crate) density: f32,
+}
+
+impl Default for Brush {
+    fn default() -> Self {
+        Brush {
+            radius: 0,
+            shape: BrushShape::Circle,
+            density: 0.1,
+        }
+    }
+}
+
+impl Brush {
+    fn contains(&self, dx: i32, dy: i32) -> bool {
+        match self.shape {
+            BrushShape::Square => dx.abs() <= self.radius && dy.abs() <= self.radius,
+            BrushShape::Circle | BrushShape::Spray => {
+                dx * dx + dy * dy <= self.radius * self.radius + self.radius
+            }
+        }
+    }
+
+    // The cells covered relative to the centre
+    pub(crate) fn offsets(&self, rng: &mut StdRng) -> Vec<(i32, i32)> {
+        let mut offsets = Vec::new();
+        for dy in -self.radius..=self.radius {
+            for dx in -self.radius..=self.radius {
+                if !self.contains(dx, dy) {
+                    continue;
+                }
+                if self.shape == BrushShape::Spray
+                    && self.radius > 0
+                    && !rng.gen_bool(self.density as f64)
+                {
+                    continue;
+                }
+                offsets.push((dx, dy));
+            }
+        }
+        offsets
+    }
+
+    // The cells around the edge of the brush relative to the centre
+    fn outline(&self) -> Vec<(i32, i32)> {
+        let mut outline = Vec::new();
+        for dy in -self.radius..=self.radius {
+            for dx in -self.radius..=self.radius {
+                if self.contains(dx, dy)
+                    && [(0, -1), (0, 1), (-1, 0), (1, 0)]
+                        .iter()
+                        .any(|(nx, ny)| !self.contains(dx + nx, dy + ny))
+                {
+                    outline.push((dx, dy));
+                }
+            }
+        }
+        outline
+    }
+}
+
+impl Simulation {
+    pub(crate) fn change_brush_radius(&mut self, delta: i32) {
+        self.brush.radius = (self.brush.radius + delta).clamp(0, MAX_BRUSH_RADIUS);
+    }
+
+    pub(crate) fn change_brush_density(&mut self, delta: f32) {
+        self.brush.density = (self.brush.density + delta).clamp(0.01, 1.0);
+    }
+
+    pub(crate) fn cycle_brush_shape(&mut self) {
+        self.brush.shape = match self.brush.shape {
+            BrushShape::Circle => BrushShape::Square,
+            BrushShape::Square => BrushShape::Spray,
+            BrushShape::Spray => BrushShape::Circle,
+        };
+    }
+
+    // Inserts the current material or source under every cell of the brush
+    pub(crate) fn paint(&mut self, x: i32, y: i32) {
+        let mut rng = self.rng();
+        let cells: Vec<(i32, i32)> = self
+            .brush
+            .offsets(&mut rng)
+            .into_iter()
+            .map(|(dx, dy)| (x + dx, y + dy))
+            .collect();
+        self.edit_cells(cells);
+    }
+
+    // Paints every cell between the end of the stroke so far and the given cell, so that
+    // fast mouse movements still leave a continuous line
+    pub(crate) fn stroke_to(&mut self, x: i32, y: i32) {
+        // The whole stroke is undone as one edit
+        if self.stroke_end.is_none() {
+            self.begin_edit();
+        }
+        let points = match self.stroke_end {
+            Some((from_x, from_y)) if (from_x, from_y) != (x, y) => {
+                // The start was painted by the previous part of the stroke
+                line_points(from_x, from_y, x, y)
+                    .into_iter()
+                    .skip(1)
+                    .collect()
+            }
+            // Holding still keeps painting the same spot
+            _ => vec![(x, y)],
+        };
+        for (px, py) in points {
+            self.paint(px, py);
+        }
+        self.stroke_end = Some((x, y));
+    }
+
+    pub(crate) fn end_stroke(&mut self) {
+        if self.stroke_end.take().is_some() {
+            self.end_edit();
+        }
+    }
+
+    pub(crate) fn update_brush_outline(&mut self) {
+        self.brush_outline = match self.cursor {
+            // A single cell brush is easier to see without an outline hiding it
+            Some((x, y)) if self.brush.radius > 0 && self.tool == Tool::Freehand => self
+                .brush
+                .outline()
+                .into_iter()
+                .filter_map(|(dx, dy)| self.index_at(x + dx, y + dy))
+                .collect(),
+            _ => HashSet::new(),
+        };
+    }
+}
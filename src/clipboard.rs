@@ -0,0 +1,495 @@
+use crate::properties::Properties;
+use crate::source::{Source, SourceShape};
+use crate::tools::Tool;
+use crate::{choose_alpha, Material, Particle, Simulation};
+use bevy::prelude::*;
+use rand::prelude::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const STAMPS_DIR: &str = "stamps";
+pub(crate) const STAMP_EXTENSION: &str = "stamp";
+
+// A rectangle of the world, with any sources in it, ready to be pasted back in
+pub(crate) struct Clipboard {
+    width: usize,
+    height: usize,
+    cells: Vec<Particle>,
+    // Indexed within the clipboard rather than the world
+    sources: Vec<(usize, Source)>,
+}
+
+impl Clipboard {
+    // Turns the contents a quarter turn clockwise
+    fn rotate(&mut self) {
+        let mut cells = vec![Particle::default(); self.cells.len()];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                cells[x * self.height + (self.height - 1 - y)] =
+                    self.cells[y * self.width + x].clone();
+            }
+        }
+        for (idx, source) in &mut self.sources {
+            let x = *idx % self.width;
+            let y = *idx / self.width;
+            *idx = x * self.height + (self.height - 1 - y);
+            source.rotate();
+        }
+        self.cells = cells;
+        std::mem::swap(&mut self.width, &mut self.height);
+    }
+
+    // Flips the contents left to right
+    fn mirror(&mut self) {
+        for y in 0..self.height {
+            self.cells[y * self.width..(y + 1) * self.width].reverse();
+        }
+        for (idx, source) in &mut self.sources {
+            let x = *idx % self.width;
+            let y = *idx / self.width;
+            *idx = y * self.width + (self.width - 1 - x);
+            source.mirror();
+        }
+    }
+
+    fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut text = format!("size {} {}\n", self.width, self.height);
+        for row in self.cells.chunks(self.width) {
+            text.extend(row.iter().map(|particle| particle.material.code()));
+            text.push('\n');
+        }
+        for (idx, source) in &self.sources {
+            let materials: Vec<String> = source
+                .materials
+                .iter()
+                .map(|(material, weight)| format!("{}:{weight}", material.code()))
+                .collect();
+            let shape = match source.shape {
+                SourceShape::Point => "point".to_string(),
+                SourceShape::Circle(radius) => format!("circle:{radius}"),
+                SourceShape::Rectangle(width, height) => format!("rect:{width}:{height}"),
+                SourceShape::Line(dx, dy) => format!("line:{dx}:{dy}"),
+            };
+            text += &format!(
+                "source {} {} {} {shape} {} {} {} {}\n",
+                idx % self.width,
+                idx / self.width,
+                source.rate,
+                source.velocity.x,
+                source.velocity.y,
+                source.fill_empty as u8,
+                materials.join(","),
+            );
+        }
+        fs::write(path, text)
+    }
+
+    fn load(path: &Path, properties: &Properties, rng: &mut impl Rng) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|error| error.to_string())?;
+        let mut lines = text.lines();
+        let size: Vec<usize> = lines
+            .next()
+            .and_then(|line| line.strip_prefix("size "))
+            .ok_or("missing size")?
+            .split_whitespace()
+            .map(|value| value.parse().map_err(|_| "invalid size"))
+            .collect::<Result<_, _>>()?;
+        let [width, height] = size[..] else {
+            return Err("invalid size".into());
+        };
+
+        let mut cells = Vec::with_capacity(width * height);
+        for _ in 0..height {
+            let row = lines.next().ok_or("missing row")?;
+            if row.chars().count() != width {
+                return Err("row does not match size".into());
+            }
+            for code in row.chars() {
+                let material = Material::from_code(code).ok_or("unknown material")?;
+                cells.push(Particle::new(material, choose_alpha(rng), properties));
+            }
+        }
+
+        let mut sources = Vec::new();
+        for line in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if let ["source", x, y, rate, shape, vx, vy, fill_empty, materials] = fields[..] {
+                let parse = |value: &str| value.parse::<i32>().map_err(|_| "invalid source");
+                let shape: Vec<&str> = shape.split(':').collect();
+                let shape = match shape[..] {
+                    ["point"] => SourceShape::Point,
+                    ["circle", radius] => SourceShape::Circle(parse(radius)?),
+                    ["rect", width, height] => {
+                        SourceShape::Rectangle(parse(width)?, parse(height)?)
+                    }
+                    ["line", dx, dy] => SourceShape::Line(parse(dx)?, parse(dy)?),
+                    _ => return Err("invalid source shape".into()),
+                };
+                let mut source = Source::new(Material::Air, parse(rate)?.max(1) as usize);
+                source.shape = shape;
+                source.velocity = IVec2::new(parse(vx)?, parse(vy)?);
+                source.fill_empty = fill_empty == "1";
+                source.materials = materials
+                    .split(',')
+                    .map(|entry| {
+                        let (code, weight) = entry.split_once(':')?;
+                        let material = Material::from_code(code.chars().next()?)?;
+                        Some((material, weight.parse().ok()?))
+                    })
+                    .collect::<Option<_>>()
+                    .ok_or("invalid source materials")?;
+                let (x, y) = (parse(x)?, parse(y)?);
+                if x < 0 || x >= width as i32 || y < 0 || y >= height as i32 {
+                    return Err(format!("source at ({x}, {y}) is outside the stamp"));
+                }
+                sources.push((y as usize * width + x as usize, source));
+            }
+        }
+
+        Ok(Clipboard {
+            width,
+            height,
+            cells,
+            sources,
+        })
+    }
+}
+
+impl Simulation {
+    // The selected rectangle as left, top, right, bottom
+    pub(crate) fn selection_bounds(&self) -> Option<(i32, i32, i32, i32)> {
+        self.selection
+            .map(|(x0, y0, x1, y1)| (x0.min(x1), y0.min(y1), x0.max(x1), y0.max(y1)))
+    }
+
+    pub(crate) fn on_selection_edge(&self, x: i32, y: i32) -> bool {
+        match self.selection_bounds() {
+            Some((left, top, right, bottom)) => {
+                let inside_x = x >= left && x <= right;
+                let inside_y = y >= top && y <= bottom;
+                (inside_x && (y == top || y == bottom)) || (inside_y && (x == left || x == right))
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn copy_selection(&mut self) {
+        let Some((left, top, right, bottom)) = self.selection_bounds() else {
+            return;
+        };
+        let width = (right - left + 1) as usize;
+        let height = (bottom - top + 1) as usize;
+        let mut cells = Vec::with_capacity(width * height);
+        let mut sources = Vec::new();
+        for y in top..=bottom {
+            for x in left..=right {
+                let idx = self.index_at(x, y);
+                cells.push(match idx {
+                    Some(idx) => self.grid[idx].clone(),
+                    None => Particle::new(Material::Air, 0.0, &self.properties),
+                });
+                if let Some(source) = idx.and_then(|idx| self.sources.get(&idx)) {
+                    let local = (y - top) as usize * width + (x - left) as usize;
+                    sources.push((local, source.clone()));
+                }
+            }
+        }
+        self.clipboard = Some(Clipboard {
+            width,
+            height,
+            cells,
+            sources,
+        });
+    }
+
+    pub(crate) fn cut_selection(&mut self) {
+        let Some((left, top, right, bottom)) = self.selection_bounds() else {
+            return;
+        };
+        self.copy_selection();
+
+        self.begin_edit();
+        for y in top..=bottom {
+            for x in left..=right {
+                if let Some(idx) = self.index_at(x, y) {
+                    self.record_cell(idx);
+                    self.record_source(idx);
+                    let alpha = self.grid[idx].alpha;
+                    self.grid[idx] = Particle::new(Material::Air, alpha, &self.properties);
+                    self.sources.remove(&idx);
+                }
+            }
+        }
+        self.selected_source = None;
+        self.end_edit();
+    }
+
+    // Starts placing the clipboard with the mouse
+    pub(crate) fn start_paste(&mut self) {
+        if self.clipboard.is_some() {
+            self.set_tool(Tool::Paste);
+        }
+    }
+
+    pub(crate) fn rotate_clipboard(&mut self) {
+        if let Some(clipboard) = &mut self.clipboard {
+            clipboard.rotate();
+        }
+    }
+
+    pub(crate) fn mirror_clipboard(&mut self) {
+        if let Some(clipboard) = &mut self.clipboard {
+            clipboard.mirror();
+        }
+    }
+
+    // The world cell each clipboard cell lands on when pasted centred on the given cell
+    fn paste_targets(&self, x: i32, y: i32) -> Vec<(usize, Option<usize>)> {
+        let Some(clipboard) = &self.clipboard else {
+            return vec![];
+        };
+        let left = x - clipboard.width as i32 / 2;
+        let top = y - clipboard.height as i32 / 2;
+        (0..clipboard.cells.len())
+            .map(|local| {
+                let cx = (local % clipboard.width) as i32;
+                let cy = (local / clipboard.width) as i32;
+                (local, self.index_at(left + cx, top + cy))
+            })
+            .collect()
+    }
+
+    pub(crate) fn update_paste_preview(&mut self, x: i32, y: i32) {
+        let Some(clipboard) = &self.clipboard else {
+            return;
+        };
+        self.preview = self
+            .paste_targets(x, y)
+            .into_iter()
+            .filter_map(|(local, idx)| {
+                let particle = &clipboard.cells[local];
+                idx.map(|idx| (idx, self.properties.color(particle.material, 1.0)))
+            })
+            .collect();
+    }
+
+    pub(crate) fn paste_at(&mut self, x: i32, y: i32) {
+        let targets = self.paste_targets(x, y);
+        let Some(clipboard) = self.clipboard.take() else {
+            return;
+        };
+
+        self.begin_edit();
+        for (local, idx) in targets {
+            if let Some(idx) = idx {
+                self.record_cell(idx);
+                self.record_source(idx);
+                self.grid[idx] = clipboard.cells[local].clone();
+                self.sources.remove(&idx);
+            }
+        }
+        for (local, source) in &clipboard.sources {
+            let x = x - clipboard.width as i32 / 2 + (local % clipboard.width) as i32;
+            let y = y - clipboard.height as i32 / 2 + (local / clipboard.width) as i32;
+            if let Some(idx) = self.index_at(x, y) {
+                self.sources.insert(idx, source.clone());
+            }
+        }
+        self.end_edit();
+        self.clipboard = Some(clipboard);
+    }
+
+    pub(crate) fn paste_file(&mut self, path: &Path) {
+        let mut rng = self.rng();
+        match Clipboard::load(path, &self.properties, &mut rng) {
+            Ok(clipboard) => {
+                self.clipboard = Some(clipboard);
+                self.paste_at(self.width as i32 / 2, self.height as i32 / 2);
+            }
+            Err(error) => println!("ERROR: Unable to load {path:?}: {error}"),
+        }
+    }
+
+    // Saves the selection under the name typed into the panel, or a numbered name if there is
+    // none. Does nothing without a selection
+    pub(crate) fn save_stamp(&mut self) {
+        if self.selection.is_none() {
+            return;
+        }
+        let name = self.stamp_name.trim();
+        if name.contains(['/', '\\', '.']) {
+            println!("ERROR: Stamp names can't contain '/', '\\' or '.': {name:?}");
+            return;
+        }
+        let path = if name.is_empty() {
+            (1..)
+                .map(|n| PathBuf::from(format!("{STAMPS_DIR}/stamp_{n:03}.{STAMP_EXTENSION}")))
+                .find(|path| !path.exists())
+                .unwrap()
+        } else {
+            PathBuf::from(format!("{STAMPS_DIR}/{name}.{STAMP_EXTENSION}"))
+        };
+        if let Err(error) = fs::create_dir_all(STAMPS_DIR) {
+            println!("ERROR: Unable to create {STAMPS_DIR:?}: {error:?}");
+            return;
+        }
+
+        self.copy_selection();
+        if let Some(clipboard) = &self.clipboard {
+            if let Err(error) = clipboard.save(&path) {
+                println!("ERROR: Unable to save stamp {path:?}: {error:?}");
+            }
+        }
+    }
+
+    pub(crate) fn stamp_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(STAMPS_DIR)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == STAMP_EXTENSION))
+            .filter_map(|path| Some(path.file_stem()?.to_string_lossy().into_owned()))
+            .collect();
+        names.sort();
+        names
+    }
+
+    pub(crate) fn load_stamp(&mut self, name: &str) {
+        let path = PathBuf::from(format!("{STAMPS_DIR}/{name}.{STAMP_EXTENSION}"));
+        let mut rng = self.rng();
+        match Clipboard::load(&path, &self.properties, &mut rng) {
+            Ok(clipboard) => {
+                self.clipboard = Some(clipboard);
+                self.stamp = Some(name.to_string());
+                self.start_paste();
+            }
+            Err(error) => println!("ERROR: Unable to load stamp {path:?}: {error}"),
+        }
+    }
+
+    // Steps through the stamps directory, ready to place each one in turn
+    pub(crate) fn next_stamp(&mut self) {
+        let names = self.stamp_names();
+        let next = match &self.stamp {
+            Some(current) => names.iter().find(|name| *name > current),
+            None => None,
+        };
+        if let Some(name) = next.or(names.first()).cloned() {
+            self.load_stamp(&name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 3 by 2 clipboard with a different material in each cell and a source in the top right
+    fn sample() -> Clipboard {
+        let materials = [
+            Material::Rock,
+            Material::Sand,
+            Material::Water,
+            Material::Oil,
+            Material::Wood,
+            Material::Air,
+        ];
+        Clipboard {
+            width: 3,
+            height: 2,
+            cells: materials
+                .iter()
+                .map(|&material| Particle::new(material, 0.5, &Properties::default()))
+                .collect(),
+            sources: vec![(2, Source::new(Material::Water, 3))],
+        }
+    }
+
+    fn load(path: &Path) -> Result<Clipboard, String> {
+        Clipboard::load(path, &Properties::default(), &mut StdRng::seed_from_u64(0))
+    }
+
+    fn materials(clipboard: &Clipboard) -> Vec<Material> {
+        clipboard.cells.iter().map(|cell| cell.material).collect()
+    }
+
+    #[test]
+    fn rotating_four_times_comes_back_round() {
+        let mut clipboard = sample();
+        clipboard.rotate();
+        assert_eq!((clipboard.width, clipboard.height), (2, 3));
+        // The top right corner ends up bottom right
+        assert_eq!(clipboard.cells[5].material, Material::Water);
+        assert_eq!(clipboard.sources[0].0, 5);
+        for _ in 0..3 {
+            clipboard.rotate();
+        }
+        assert_eq!(materials(&clipboard), materials(&sample()));
+        assert_eq!(clipboard.sources[0].0, 2);
+    }
+
+    #[test]
+    fn mirroring_twice_comes_back_round() {
+        let mut clipboard = sample();
+        clipboard.mirror();
+        assert_eq!(clipboard.cells[0].material, Material::Water);
+        assert_eq!(clipboard.sources[0].0, 0);
+        clipboard.mirror();
+        assert_eq!(materials(&clipboard), materials(&sample()));
+        assert_eq!(clipboard.sources[0].0, 2);
+    }
+
+    #[test]
+    fn sources_turn_with_the_stamp() {
+        let mut clipboard = sample();
+        clipboard.sources.push((0, Source::new(Material::Sand, 1)));
+        clipboard.sources[0].1.shape = SourceShape::Line(4, 1);
+        clipboard.sources[0].1.velocity = IVec2::new(3, 0);
+        clipboard.sources[1].1.shape = SourceShape::Rectangle(5, 2);
+
+        clipboard.rotate();
+        assert_eq!(clipboard.sources[0].1.shape, SourceShape::Line(-1, 4));
+        assert_eq!(clipboard.sources[0].1.velocity, IVec2::new(0, 3));
+        assert_eq!(clipboard.sources[1].1.shape, SourceShape::Rectangle(2, 5));
+
+        clipboard.mirror();
+        assert_eq!(clipboard.sources[0].1.shape, SourceShape::Line(1, 4));
+        assert_eq!(clipboard.sources[0].1.velocity, IVec2::new(0, 3));
+        assert_eq!(clipboard.sources[1].1.shape, SourceShape::Rectangle(2, 5));
+    }
+
+    #[test]
+    fn saved_stamps_load_back_the_same() {
+        let path = std::env::temp_dir().join("particles_round_trip.stamp");
+        sample().save(&path).unwrap();
+        let loaded = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!((loaded.width, loaded.height), (3, 2));
+        assert_eq!(materials(&loaded), materials(&sample()));
+        assert_eq!(loaded.sources.len(), 1);
+        assert_eq!(loaded.sources[0].0, 2);
+        assert_eq!(loaded.sources[0].1.rate, 3);
+        assert_eq!(loaded.sources[0].1.materials, vec![(Material::Water, 1)]);
+    }
+
+    #[test]
+    fn sources_outside_the_stamp_are_rejected() {
+        let path = std::env::temp_dir().join("particles_bad_source.stamp");
+        fs::write(&path, "size 2 1\nrs\nsource 5 0 3 point 0 1 0 w:1\n").unwrap();
+        let result = load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn uneven_rows_are_rejected() {
+        // The right number of cells in total, but not in each row
+        let path = std::env::temp_dir().join("particles_uneven_rows.stamp");
+        fs::write(&path, "size 2 2\nrsw\nr\n").unwrap();
+        let result = load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
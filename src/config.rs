@@ -0,0 +1,305 @@
+use crate::generate::{Generator, WorldSettings, MAX_HILLS, MAX_HILL_WIDTH, MIN_HILL_WIDTH};
+use crate::import::ImportMode;
+use crate::{Material, GRID_HEIGHT, GRID_WIDTH, MIN_PIXEL_SIZE};
+use bevy::prelude::*;
+use std::fs;
+use std::path::PathBuf;
+use strum::IntoEnumIterator;
+
+const CONFIG_FILE: &str = "particles.conf";
+const MAX_GRID_SIZE: usize = 4000;
+const MAX_PIXEL_SIZE: usize = 32;
+
+const USAGE: &str = "Usage: particles [options]
+
+Options:
+  --config PATH      read settings from PATH instead of particles.conf
+  --width N          grid width in cells
+  --height N         grid height in cells
+  --pixel-size N     screen pixels per cell
+  --seed N           seed for the random number generator, to repeat a run
+  --scene PATH       image or stamp file to load at startup
+  --material NAME    material selected at startup
+  --import MODE      how the scene and dropped images are read: picture, heightmap or mask
+  --paused           start with the simulation paused
+  --running          start with the simulation running, even if the config file pauses it
+  --world NAME       world to generate at startup: noise, terrain or caverns
+  --ground F         average height of the ground, as a fraction of the world height
+  --hills F          how far hills rise and fall, as a fraction of the world height, up to 0.5
+  --hill-width N     width of a typical hill in cells, from 10 to 500
+  --lakes F          height of the water line, flooding the valleys below it
+  --caves F          how much of the ground is hollowed into caves, from 0 to 1
+  --oil F            how much of the rock holds pockets of oil, from 0 to 1
+  --help             show this message
+
+The config file takes the same settings as `key = value` lines, for example `pixel-size = 3`.
+Command-line options override the config file. World settings given alongside a world replace
+its defaults, whichever order they come in.";
+
+#[derive(Resource, Clone)]
+pub(crate) struct Config {
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    pub(crate) pixel_size: usize,
+    pub(crate) seed: Option<u64>,
+    pub(crate) scene: Option<PathBuf>,
+    pub(crate) material: Material,
+    pub(crate) paused: bool,
+    pub(crate) import_mode: ImportMode,
+    generator: Generator,
+    world_overrides: WorldOverrides,
+}
+
+// World settings given on their own, laid over the defaults of the chosen world so that the order
+// they are given in doesn't matter
+#[derive(Clone, Copy, Default)]
+struct WorldOverrides {
+    ground: Option<f32>,
+    hills: Option<f32>,
+    hill_width: Option<f32>,
+    lakes: Option<f32>,
+    caves: Option<f32>,
+    oil: Option<f32>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            width: GRID_WIDTH,
+            height: GRID_HEIGHT,
+            pixel_size: MIN_PIXEL_SIZE,
+            seed: None,
+            scene: None,
+            material: Material::Rock,
+            paused: false,
+            import_mode: ImportMode::Picture,
+            generator: Generator::Terrain,
+            world_overrides: WorldOverrides::default(),
+        }
+    }
+}
+
+fn parse_size(key: &str, value: &str, min: usize, max: usize) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(size) if (min..=max).contains(&size) => Ok(size),
+        _ => Err(format!(
+            "{key} must be a whole number from {min} to {max}, not {value:?}"
+        )),
+    }
+}
+
+fn parse_material(value: &str) -> Result<Material, String> {
+    Material::iter()
+        .find(|material| format!("{material:?}").eq_ignore_ascii_case(value))
+        .or_else(|| match value.chars().collect::<Vec<_>>()[..] {
+            [code] => Material::from_code(code.to_ascii_lowercase()),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            let names: Vec<String> = Material::iter().map(|m| format!("{m:?}")).collect();
+            format!(
+                "material must be one of {}, not {value:?}",
+                names.join(", ")
+            )
+        })
+}
+
+fn parse_fraction(key: &str, value: &str, max: f32) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(fraction) if (0.0..=max).contains(&fraction) => Ok(fraction),
+        _ => Err(format!(
+            "{key} must be a number from 0 to {max}, not {value:?}"
+        )),
+    }
+}
+
+fn parse_generator(value: &str) -> Result<Generator, String> {
+    Generator::iter()
+        .find(|generator| format!("{generator:?}").eq_ignore_ascii_case(value))
+        .ok_or_else(|| {
+            let names: Vec<String> = Generator::iter().map(|g| format!("{g:?}")).collect();
+            format!("world must be one of {}, not {value:?}", names.join(", "))
+        })
+}
+
+fn parse_import_mode(value: &str) -> Result<ImportMode, String> {
+    ImportMode::iter()
+        .find(|mode| format!("{mode:?}").eq_ignore_ascii_case(value))
+        .ok_or_else(|| {
+            let names: Vec<String> = ImportMode::iter().map(|m| format!("{m:?}")).collect();
+            format!("import must be one of {}, not {value:?}", names.join(", "))
+        })
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "yes" | "1" => Ok(true),
+        "false" | "no" | "0" => Ok(false),
+        _ => Err(format!("{key} must be true or false, not {value:?}")),
+    }
+}
+
+impl Config {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "width" => self.width = parse_size(key, value, 10, MAX_GRID_SIZE)?,
+            "height" => self.height = parse_size(key, value, 10, MAX_GRID_SIZE)?,
+            "pixel-size" => {
+                self.pixel_size = parse_size(key, value, MIN_PIXEL_SIZE, MAX_PIXEL_SIZE)?
+            }
+            "seed" => {
+                let seed = value
+                    .parse()
+                    .map_err(|_| format!("seed must be a whole number, not {value:?}"))?;
+                self.seed = Some(seed);
+            }
+            "scene" => {
+                let path = PathBuf::from(value);
+                if !path.is_file() {
+                    return Err(format!("scene file {path:?} does not exist"));
+                }
+                self.scene = Some(path);
+            }
+            "material" => self.material = parse_material(value)?,
+            "paused" => self.paused = parse_bool(key, value)?,
+            "import" => self.import_mode = parse_import_mode(value)?,
+            "world" => self.generator = parse_generator(value)?,
+            "ground" => self.world_overrides.ground = Some(parse_fraction(key, value, 1.0)?),
+            "hills" => self.world_overrides.hills = Some(parse_fraction(key, value, MAX_HILLS)?),
+            "hill-width" => {
+                let width = parse_size(key, value, MIN_HILL_WIDTH, MAX_HILL_WIDTH)?;
+                self.world_overrides.hill_width = Some(width as f32);
+            }
+            "lakes" => self.world_overrides.lakes = Some(parse_fraction(key, value, 1.0)?),
+            "caves" => self.world_overrides.caves = Some(parse_fraction(key, value, 1.0)?),
+            "oil" => self.world_overrides.oil = Some(parse_fraction(key, value, 1.0)?),
+            _ => return Err(format!("unknown setting {key:?}")),
+        }
+        Ok(())
+    }
+
+    // The chosen world's defaults with any settings given for it on top
+    pub(crate) fn world(&self) -> WorldSettings {
+        let defaults = self.generator.settings(0);
+        let overrides = self.world_overrides;
+        WorldSettings {
+            ground: overrides.ground.unwrap_or(defaults.ground),
+            hills: overrides.hills.unwrap_or(defaults.hills),
+            hill_width: overrides.hill_width.unwrap_or(defaults.hill_width),
+            lakes: overrides.lakes.unwrap_or(defaults.lakes),
+            caves: overrides.caves.unwrap_or(defaults.caves),
+            oil: overrides.oil.unwrap_or(defaults.oil),
+            ..defaults
+        }
+    }
+
+    fn read_file(&mut self, path: &str) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|error| format!("{path:?}: {error}"))?;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("{path:?} line {}: expected key = value", number + 1))?;
+            self.set(key.trim(), value.trim())
+                .map_err(|error| format!("{path:?} line {}: {error}", number + 1))?;
+        }
+        Ok(())
+    }
+
+    // Settings from the config file, if there is one, overridden by the command line
+    pub(crate) fn load() -> Result<Config, String> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+            println!("{USAGE}");
+            std::process::exit(0);
+        }
+
+        let mut config = Config::default();
+        match args.iter().position(|arg| arg == "--config") {
+            Some(idx) => {
+                let path = args.get(idx + 1).ok_or("--config needs a path")?;
+                config.read_file(path)?;
+            }
+            None if PathBuf::from(CONFIG_FILE).is_file() => config.read_file(CONFIG_FILE)?,
+            None => {}
+        }
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let Some(key) = arg.strip_prefix("--") else {
+                return Err(format!("unexpected argument {arg:?}\n\n{USAGE}"));
+            };
+            match key {
+                "paused" => config.paused = true,
+                "running" => config.paused = false,
+                "config" => {
+                    args.next();
+                }
+                "width" | "height" | "pixel-size" | "seed" | "scene" | "material" | "import"
+                | "world" | "ground" | "hills" | "hill-width" | "lakes" | "caves" | "oil" => {
+                    let value = args.next().ok_or(format!("{arg} needs a value"))?;
+                    config.set(key, value)?;
+                }
+                _ => return Err(format!("unknown option {arg:?}\n\n{USAGE}")),
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_are_checked_as_they_are_set() {
+        let mut config = Config::default();
+        config.set("width", "300").unwrap();
+        config.set("material", "water").unwrap();
+        config.set("paused", "yes").unwrap();
+        config.set("import", "Heightmap").unwrap();
+        assert_eq!(config.width, 300);
+        assert_eq!(config.material, Material::Water);
+        assert!(config.paused);
+        assert_eq!(config.import_mode, ImportMode::Heightmap);
+
+        config.set("material", "s").unwrap();
+        assert_eq!(config.material, Material::Sand);
+        config.set("paused", "false").unwrap();
+        assert!(!config.paused);
+    }
+
+    #[test]
+    fn bad_settings_are_rejected() {
+        let mut config = Config::default();
+        assert!(config.set("width", "5").is_err());
+        assert!(config.set("pixel-size", "lots").is_err());
+        assert!(config.set("material", "cheese").is_err());
+        assert!(config.set("lakes", "1.5").is_err());
+        assert!(config.set("hills", "0.8").is_err());
+        assert!(config.set("hill-width", "5").is_err());
+        assert!(config.set("hill-width", "600").is_err());
+        assert!(config.set("colour", "red").is_err());
+        assert_eq!(config.width, GRID_WIDTH);
+    }
+
+    #[test]
+    fn world_settings_do_not_depend_on_order() {
+        let mut before = Config::default();
+        before.set("lakes", "0.2").unwrap();
+        before.set("world", "caverns").unwrap();
+        let mut after = Config::default();
+        after.set("world", "caverns").unwrap();
+        after.set("lakes", "0.2").unwrap();
+
+        for config in [before, after] {
+            let world = config.world();
+            assert_eq!(world.generator, Generator::Caverns);
+            assert_eq!(world.lakes, 0.2);
+            assert_eq!(world.ground, Generator::Caverns.settings(0).ground);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::generate::lattice;
+use crate::{Material, Simulation};
+use rand::prelude::*;
+use strum::IntoEnumIterator;
+
+pub(crate) const MAX_BLOB_SIZE: usize = 64;
+
+// A weighted mixture of materials to fill the world or the selection with
+pub(crate) struct Fill {
+    // Every material in order, most with no weight
+    pub(crate) materials: Vec<(Material, u32)>,
+    // Rough width in cells of the patches of each material, with 1 picking every cell separately
+    pub(crate) blob_size: usize,
+}
+
+impl Default for Fill {
+    // The same materials that random worlds are made of
+    fn default() -> Self {
+        Fill {
+            materials: Material::iter()
+                .map(|material| match material {
+                    Material::Gas
+                    | Material::Air
+                    | Material::Oil
+                    | Material::Water
+                    | Material::Sand
+                    | Material::Rock => (material, 10),
+                    _ => (material, 0),
+                })
+                .collect(),
+            blob_size: 1,
+        }
+    }
+}
+
+impl Fill {
+    fn total(&self) -> u32 {
+        self.materials.iter().map(|(_, weight)| weight).sum()
+    }
+
+    // Share of the mixture taken by one material, as a percentage
+    pub(crate) fn percentage(&self, material: Material) -> f32 {
+        let weight = self
+            .materials
+            .iter()
+            .find(|(m, _)| *m == material)
+            .map_or(0, |(_, weight)| *weight);
+        weight as f32 * 100.0 / self.total().max(1) as f32
+    }
+
+    // The material a choice from 0 to 1 lands on
+    fn choose(&self, choice: f32) -> Material {
+        let mut choice = (choice * self.total() as f32) as u32;
+        for (material, weight) in &self.materials {
+            if choice < *weight {
+                return *material;
+            }
+            choice -= weight;
+        }
+        Material::Air
+    }
+
+    // Blobs grow around one point in each square of the blob size, scattered at random, with
+    // every cell taking the material of the nearest point
+    fn blob_material(&self, seed: u64, x: i32, y: i32) -> Material {
+        let size = self.blob_size as f32;
+        let (cx, cy) = (
+            (x as f32 / size).floor() as i64,
+            (y as f32 / size).floor() as i64,
+        );
+        let mut nearest = (f32::MAX, 0.0);
+        for sy in cy - 1..=cy + 1 {
+            for sx in cx - 1..=cx + 1 {
+                let px = (sx as f32 + lattice(seed, sx, sy)) * size;
+                let py = (sy as f32 + lattice(seed ^ 1, sx, sy)) * size;
+                let distance = (px - x as f32).powi(2) + (py - y as f32).powi(2);
+                if distance < nearest.0 {
+                    nearest = (distance, lattice(seed ^ 2, sx, sy));
+                }
+            }
+        }
+        self.choose(nearest.1)
+    }
+}
+
+impl Simulation {
+    // Fills a rectangle, given as left, top, right, bottom, with the fill mixture
+    pub(crate) fn fill_mixture(&mut self, (left, top, right, bottom): (i32, i32, i32, i32)) {
+        if self.fill.total() == 0 {
+            return;
+        }
+        self.begin_edit();
+        let mut rng = self.rng();
+        let seed = rng.gen();
+        for y in top..=bottom {
+            for x in left..=right {
+                let Some(idx) = self.index_at(x, y) else {
+                    continue;
+                };
+                let material = if self.fill.blob_size > 1 {
+                    self.fill.blob_material(seed, x, y)
+                } else {
+                    self.fill.choose(rng.gen())
+                };
+                self.record_cell(idx);
+                self.grid[idx].set_material(material, &self.properties);
+            }
+        }
+        self.end_edit();
+    }
+
+    pub(crate) fn fill_all_mixture(&mut self) {
+        self.fill_mixture((0, 0, self.width as i32 - 1, self.height as i32 - 1));
+    }
+
+    pub(crate) fn fill_selection_mixture(&mut self) {
+        if let Some(bounds) = self.selection_bounds() {
+            self.fill_mixture(bounds);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn only(weights: &[(Material, u32)]) -> Fill {
+        let mut fill = Fill::default();
+        for (material, weight) in &mut fill.materials {
+            *weight = weights
+                .iter()
+                .find(|(m, _)| m == material)
+                .map_or(0, |(_, w)| *w);
+        }
+        fill
+    }
+
+    #[test]
+    fn percentages_share_out_the_weights() {
+        let fill = only(&[(Material::Sand, 3), (Material::Water, 1)]);
+        assert_eq!(fill.percentage(Material::Sand), 75.0);
+        assert_eq!(fill.percentage(Material::Water), 25.0);
+        assert_eq!(fill.percentage(Material::Rock), 0.0);
+        assert_eq!(only(&[]).percentage(Material::Rock), 0.0);
+    }
+
+    #[test]
+    fn choices_land_in_proportion_to_the_weights() {
+        let fill = only(&[(Material::Sand, 3), (Material::Water, 1)]);
+        // Water comes before Sand in the list of materials
+        assert_eq!(fill.choose(0.0), Material::Water);
+        assert_eq!(fill.choose(0.2), Material::Water);
+        assert_eq!(fill.choose(0.3), Material::Sand);
+        assert_eq!(fill.choose(0.99), Material::Sand);
+    }
+
+    #[test]
+    fn blobs_are_patches_of_one_material() {
+        let mut fill = only(&[(Material::Sand, 1), (Material::Water, 1)]);
+        fill.blob_size = 16;
+        let materials: Vec<Material> = (0..64)
+            .flat_map(|y| (0..64).map(move |x| (x, y)))
+            .map(|(x, y)| fill.blob_material(7, x, y))
+            .collect();
+        assert!(materials.contains(&Material::Sand));
+        assert!(materials.contains(&Material::Water));
+        // The same seed gives the same blobs
+        assert_eq!(fill.blob_material(7, 10, 20), materials[20 * 64 + 10]);
+        // Far fewer changes from one cell to the next than picking every cell separately
+        let changes = materials
+            .windows(2)
+            .filter(|pair| pair[0] != pair[1])
+            .count();
+        assert!(changes < materials.len() / 8, "{changes} changes");
+    }
+
+    #[test]
+    fn filling_a_selection_stays_inside_it() {
+        let mut simulation = Simulation::empty(6, 6);
+        simulation.fill = only(&[(Material::Rock, 1)]);
+        simulation.fill_mixture((1, 1, 3, 2));
+        let rock: Vec<usize> = (0..36)
+            .filter(|&idx| simulation.grid[idx].material == Material::Rock)
+            .collect();
+        assert_eq!(rock, vec![7, 8, 9, 13, 14, 15]);
+    }
+}
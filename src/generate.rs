@@ -0,0 +1,265 @@
+use crate::properties::Properties;
+use crate::{choose_alpha, choose_random_material, Material, Particle, Simulation};
+use rand::prelude::*;
+use strum_macros::EnumIter;
+
+// Limits shared by the panel and the config file
+pub(crate) const MAX_HILLS: f32 = 0.5;
+pub(crate) const MIN_HILL_WIDTH: usize = 10;
+pub(crate) const MAX_HILL_WIDTH: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, EnumIter)]
+pub(crate) enum Generator {
+    // Every cell a random material, as the simulator always used to start
+    Noise,
+    // Hills under open sky, with lakes in the valleys
+    Terrain,
+    // Solid rock riddled with caves, just under the surface
+    Caverns,
+}
+
+// Everything needed to build a world again, the seed included
+#[derive(Clone, Copy)]
+pub(crate) struct WorldSettings {
+    pub(crate) generator: Generator,
+    pub(crate) seed: u64,
+    // Average height of the ground, as a fraction of the world height
+    pub(crate) ground: f32,
+    // How far hills rise above and valleys sink below it
+    pub(crate) hills: f32,
+    // Width of a typical hill, in cells
+    pub(crate) hill_width: f32,
+    // Height of the water line, with valleys below it flooded
+    pub(crate) lakes: f32,
+    // Roughly how much of the ground is hollowed out
+    pub(crate) caves: f32,
+    pub(crate) oil: f32,
+}
+
+impl Generator {
+    // The settings each generator starts from
+    pub(crate) fn settings(&self, seed: u64) -> WorldSettings {
+        let settings = WorldSettings {
+            generator: *self,
+            seed,
+            ground: 0.45,
+            hills: 0.15,
+            hill_width: 120.0,
+            lakes: 0.38,
+            caves: 0.2,
+            oil: 0.15,
+        };
+        match self {
+            Generator::Noise | Generator::Terrain => settings,
+            Generator::Caverns => WorldSettings {
+                ground: 0.9,
+                hills: 0.03,
+                hill_width: 60.0,
+                lakes: 0.0,
+                caves: 0.35,
+                oil: 0.2,
+                ..settings
+            },
+        }
+    }
+}
+
+// A random value for each point of a lattice, the same every time for the same seed
+pub(crate) fn lattice(seed: u64, x: i64, y: i64) -> f32 {
+    let mut hash = seed
+        ^ (x as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        ^ (y as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^= hash >> 33;
+    (hash >> 40) as f32 / (1 << 24) as f32
+}
+
+// Smoothly blends the lattice values around a point
+fn value_noise(seed: u64, x: f32, y: f32) -> f32 {
+    let (x0, y0) = (x.floor(), y.floor());
+    let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+    let (tx, ty) = (smooth(x - x0), smooth(y - y0));
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let top = lattice(seed, x0, y0) * (1.0 - tx) + lattice(seed, x0 + 1, y0) * tx;
+    let bottom = lattice(seed, x0, y0 + 1) * (1.0 - tx) + lattice(seed, x0 + 1, y0 + 1) * tx;
+    top * (1.0 - ty) + bottom * ty
+}
+
+// Layers of noise, each finer and fainter than the last, from 0 to 1
+fn fractal_noise(seed: u64, x: f32, y: f32) -> f32 {
+    let mut total = 0.0;
+    let mut weight = 1.0;
+    let mut weights = 0.0;
+    for octave in 0..4 {
+        let scale = (1 << octave) as f32;
+        total += value_noise(seed.wrapping_add(octave), x * scale, y * scale) * weight;
+        weights += weight;
+        weight *= 0.5;
+    }
+    total / weights
+}
+
+fn terrain(settings: &WorldSettings, width: usize, height: usize) -> Vec<Material> {
+    let seed = settings.seed;
+    let height_f = height as f32;
+    let hill_width = settings.hill_width.max(1.0);
+    // Row of the ground surface in each column, counted down from the top
+    let surface: Vec<f32> = (0..width)
+        .map(|x| {
+            let hill = fractal_noise(seed, x as f32 / hill_width, 0.5) * 2.0 - 1.0;
+            height_f * (1.0 - settings.ground - settings.hills * hill * 2.0)
+        })
+        .collect();
+    let water_line = height_f * (1.0 - settings.lakes);
+    // Sand over the rock
+    let soil = (height / 40).max(2) as f32;
+    let cave_size = hill_width / 3.0;
+    let oil_size = hill_width / 8.0;
+
+    let mut materials = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for (x, surface) in surface.iter().enumerate() {
+            let row = y as f32 + 0.5;
+            let depth = row - surface;
+            let (fx, fy) = (x as f32, y as f32);
+            // Caves follow the narrow bands where the noise is close to a half
+            let cave = (fractal_noise(seed ^ 1, fx / cave_size, fy / cave_size) - 0.5).abs()
+                < settings.caves * 0.15;
+            let oil =
+                fractal_noise(seed ^ 2, fx / oil_size, fy / oil_size) > 0.75 - settings.oil * 0.5;
+            let material = if depth < 0.0 {
+                if settings.lakes > 0.0 && row > water_line {
+                    Material::Water
+                } else {
+                    Material::Air
+                }
+            } else if depth > soil && cave {
+                Material::Air
+            } else if depth > soil * 3.0 && oil {
+                Material::Oil
+            } else if depth < soil {
+                Material::Sand
+            } else {
+                Material::Rock
+            };
+            materials.push(material);
+        }
+    }
+    materials
+}
+
+pub(crate) fn generate_grid(
+    settings: &WorldSettings,
+    width: usize,
+    height: usize,
+    properties: &Properties,
+) -> Vec<Particle> {
+    let mut rng = StdRng::seed_from_u64(settings.seed);
+    let materials = match settings.generator {
+        Generator::Noise => (0..width * height)
+            .map(|_| choose_random_material(&mut rng))
+            .collect(),
+        Generator::Terrain | Generator::Caverns => terrain(settings, width, height),
+    };
+    materials
+        .into_iter()
+        .map(|material| Particle::new(material, choose_alpha(&mut rng), properties))
+        .collect()
+}
+
+impl Simulation {
+    // Rebuilds the world from the current world settings
+    pub(crate) fn generate_world(&mut self) {
+        self.begin_edit();
+        self.record_all_cells();
+        self.grid = generate_grid(&self.world, self.width, self.height, &self.properties);
+        self.end_edit();
+    }
+
+    // Builds another world of the same kind
+    pub(crate) fn reset_random(&mut self) {
+        self.world.seed = self.rng.gen();
+        self.generate_world();
+    }
+
+    pub(crate) fn set_generator(&mut self, generator: Generator) {
+        self.world = generator.settings(self.world.seed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn materials(settings: &WorldSettings, width: usize, height: usize) -> Vec<Material> {
+        generate_grid(settings, width, height, &Properties::default())
+            .iter()
+            .map(|particle| particle.material)
+            .collect()
+    }
+
+    #[test]
+    fn the_same_seed_builds_the_same_world() {
+        for generator in [Generator::Noise, Generator::Terrain, Generator::Caverns] {
+            let settings = generator.settings(7);
+            let first = generate_grid(&settings, 60, 40, &Properties::default());
+            let second = generate_grid(&settings, 60, 40, &Properties::default());
+            assert!(first
+                .iter()
+                .zip(&second)
+                .all(|(a, b)| a.material == b.material && a.alpha == b.alpha));
+            assert_ne!(
+                materials(&settings, 60, 40),
+                materials(&generator.settings(8), 60, 40)
+            );
+        }
+    }
+
+    #[test]
+    fn the_water_line_follows_lakes() {
+        // Flat ground 30 rows up, so everything above it and below the water line floods
+        let flat = WorldSettings {
+            ground: 0.3,
+            hills: 0.0,
+            caves: 0.0,
+            oil: 0.0,
+            ..Generator::Terrain.settings(0)
+        };
+        for (lakes, top_of_water) in [(0.5, Some(50)), (0.6, Some(40)), (0.0, None)] {
+            let world = materials(&WorldSettings { lakes, ..flat }, 10, 100);
+            for x in 0..10 {
+                let column: Vec<Material> = (0..100).map(|y| world[y * 10 + x]).collect();
+                assert_eq!(
+                    column.iter().position(|&m| m == Material::Water),
+                    top_of_water
+                );
+                assert!(column[..70]
+                    .iter()
+                    .all(|&m| matches!(m, Material::Air | Material::Water)));
+                assert!(column[70..]
+                    .iter()
+                    .all(|&m| !matches!(m, Material::Air | Material::Water)));
+            }
+        }
+    }
+
+    #[test]
+    fn no_caves_leaves_the_ground_solid() {
+        let settings = WorldSettings {
+            caves: 0.0,
+            ..Generator::Caverns.settings(3)
+        };
+        let world = materials(&settings, 80, 60);
+        for x in 0..80 {
+            let column: Vec<Material> = (0..60).map(|y| world[y * 80 + x]).collect();
+            let surface = column.iter().position(|&m| m != Material::Air).unwrap();
+            assert!(
+                column[surface..].iter().all(|&m| m != Material::Air),
+                "column {x}"
+            );
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use crate::source::Source;
+use crate::{Particle, Simulation, Sink};
+use std::collections::{HashMap, VecDeque};
+
+const MAX_HISTORY: usize = 30;
+// Whole-world edits store every cell, so also limit how many cells are kept in total
+const MAX_HISTORY_CELLS: usize = 1_000_000;
+
+// The state of everything an edit changed, before and after
+struct Edit {
+    cells: Vec<(usize, Particle, Particle)>,
+    sources: Vec<(usize, Option<Source>, Option<Source>)>,
+    sinks: Vec<(usize, Option<Sink>, Option<Sink>)>,
+}
+
+#[derive(Default)]
+struct OpenEdit {
+    cells: HashMap<usize, Particle>,
+    // What each cell was left as by the edit itself. A stroke stays open while the simulation
+    // runs, so reading the grid when it closes would also pick up whatever has moved since
+    written: HashMap<usize, Particle>,
+    // Cells recorded since the last nested edit closed, still to have their result taken
+    unwritten: Vec<usize>,
+    sources: HashMap<usize, Option<Source>>,
+    sinks: HashMap<usize, Option<Sink>>,
+}
+
+#[derive(Default)]
+pub(crate) struct History {
+    undo: VecDeque<Edit>,
+    redo: Vec<Edit>,
+    open: Option<OpenEdit>,
+    // Edits nest, so a stroke made of many brush dabs is undone in one go
+    depth: usize,
+}
+
+impl History {
+    fn cell_count(&self) -> usize {
+        self.undo.iter().map(|edit| edit.cells.len()).sum()
+    }
+}
+
+impl Simulation {
+    pub(crate) fn begin_edit(&mut self) {
+        if self.history.depth == 0 {
+            self.history.open = Some(OpenEdit::default());
+        }
+        self.history.depth += 1;
+    }
+
+    pub(crate) fn end_edit(&mut self) {
+        if let Some(open) = &mut self.history.open {
+            for idx in open.unwritten.drain(..) {
+                open.written.insert(idx, self.grid[idx].clone());
+            }
+        }
+        self.history.depth = self.history.depth.saturating_sub(1);
+        if self.history.depth > 0 {
+            return;
+        }
+        let Some(mut open) = self.history.open.take() else {
+            return;
+        };
+
+        let edit = Edit {
+            cells: open
+                .cells
+                .into_iter()
+                .map(|(idx, before)| (idx, before, open.written.remove(&idx).unwrap()))
+                .collect(),
+            sources: open
+                .sources
+                .into_iter()
+                .map(|(idx, before)| (idx, before, self.sources.get(&idx).cloned()))
+                .collect(),
+            sinks: open
+                .sinks
+                .into_iter()
+                .map(|(idx, before)| (idx, before, self.sinks.get(&idx).cloned()))
+                .collect(),
+        };
+        if edit.cells.is_empty() && edit.sources.is_empty() && edit.sinks.is_empty() {
+            return;
+        }
+
+        self.history.undo.push_back(edit);
+        self.history.redo.clear();
+        while self.history.undo.len() > MAX_HISTORY
+            || (self.history.undo.len() > 1 && self.history.cell_count() > MAX_HISTORY_CELLS)
+        {
+            self.history.undo.pop_front();
+        }
+    }
+
+    // Remembers the state of a cell before the open edit first changes it
+    pub(crate) fn record_cell(&mut self, idx: usize) {
+        if let Some(open) = &mut self.history.open {
+            open.cells
+                .entry(idx)
+                .or_insert_with(|| self.grid[idx].clone());
+            open.unwritten.push(idx);
+        }
+    }
+
+    pub(crate) fn record_all_cells(&mut self) {
+        for idx in 0..self.grid.len() {
+            self.record_cell(idx);
+        }
+    }
+
+    pub(crate) fn record_source(&mut self, idx: usize) {
+        if let Some(open) = &mut self.history.open {
+            open.sources
+                .entry(idx)
+                .or_insert_with(|| self.sources.get(&idx).cloned());
+        }
+    }
+
+    pub(crate) fn record_sink(&mut self, idx: usize) {
+        if let Some(open) = &mut self.history.open {
+            open.sinks
+                .entry(idx)
+                .or_insert_with(|| self.sinks.get(&idx).cloned());
+        }
+    }
+
+    // Indices no longer refer to the same cells once the grid changes size
+    pub(crate) fn clear_history(&mut self) {
+        self.history = History::default();
+    }
+
+    pub(crate) fn undo(&mut self) {
+        if let Some(edit) = self.history.undo.pop_back() {
+            for (idx, before, _) in &edit.cells {
+                self.grid[*idx] = before.clone();
+            }
+            for (idx, before, _) in &edit.sources {
+                set_or_remove(&mut self.sources, *idx, before);
+            }
+            for (idx, before, _) in &edit.sinks {
+                set_or_remove(&mut self.sinks, *idx, before);
+            }
+            self.selected_source = None;
+            self.history.redo.push(edit);
+        }
+    }
+
+    pub(crate) fn redo(&mut self) {
+        if let Some(edit) = self.history.redo.pop() {
+            for (idx, _, after) in &edit.cells {
+                self.grid[*idx] = after.clone();
+            }
+            for (idx, _, after) in &edit.sources {
+                set_or_remove(&mut self.sources, *idx, after);
+            }
+            for (idx, _, after) in &edit.sinks {
+                set_or_remove(&mut self.sinks, *idx, after);
+            }
+            self.selected_source = None;
+            self.history.undo.push_back(edit);
+        }
+    }
+}
+
+fn set_or_remove<T: Clone>(map: &mut HashMap<usize, T>, idx: usize, value: &Option<T>) {
+    match value {
+        Some(value) => {
+            map.insert(idx, value.clone());
+        }
+        None => {
+            map.remove(&idx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{InsertMode, Material, Simulation};
+
+    #[test]
+    fn painting_over_nothing_leaves_no_undo_step() {
+        let mut simulation = Simulation::empty(3, 3);
+        simulation.put(1, 1, Material::Rock);
+        simulation.only_over_air = true;
+        simulation.set_material(Material::Sand, InsertMode::Material);
+
+        simulation.edit_cells([(1, 1)]);
+        assert!(simulation.history.undo.is_empty());
+
+        simulation.edit_cells([(0, 0)]);
+        assert_eq!(simulation.history.undo.len(), 1);
+        simulation.undo();
+        assert_eq!(simulation.grid[0].material, Material::Air);
+        assert_eq!(simulation.grid[4].material, Material::Rock);
+    }
+
+    #[test]
+    fn strokes_keep_what_the_brush_wrote_not_what_the_simulation_did() {
+        let mut simulation = Simulation::empty(3, 3);
+        simulation.set_material(Material::Sand, InsertMode::Material);
+
+        simulation.stroke_to(1, 0);
+        // The sand falls while the stroke is still going
+        simulation.grid.swap(1, 4);
+        simulation.stroke_to(2, 0);
+        simulation.end_stroke();
+        assert_eq!(simulation.history.undo.len(), 1);
+
+        simulation.undo();
+        let materials: Vec<Material> = simulation.grid.iter().map(|p| p.material).collect();
+        assert_eq!(materials[..3], [Material::Air; 3]);
+        assert_eq!(materials[4], Material::Sand);
+
+        simulation.redo();
+        assert_eq!(simulation.grid[1].material, Material::Sand);
+        assert_eq!(simulation.grid[2].material, Material::Sand);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::{Material, Simulation};
+use image::{imageops, DynamicImage, LumaA};
+use std::path::PathBuf;
+use strum_macros::EnumIter;
+
+// How a dropped image is turned into the world
+#[derive(Debug, Clone, Copy, PartialEq, EnumIter)]
+pub(crate) enum ImportMode {
+    // Each pixel becomes the material closest to its colour, keeping the colour
+    Picture,
+    // Brightness down each column gives the height of the ground, lighter being higher
+    Heightmap,
+    // Light pixels are painted with the selected material
+    Mask,
+}
+
+impl ImportMode {
+    fn next(&self) -> ImportMode {
+        match self {
+            ImportMode::Picture => ImportMode::Heightmap,
+            ImportMode::Heightmap => ImportMode::Mask,
+            ImportMode::Mask => ImportMode::Picture,
+        }
+    }
+}
+
+fn open_image(path: &PathBuf) -> Option<DynamicImage> {
+    match image::open(path) {
+        Ok(img) => Some(img),
+        Err(error) => {
+            println!("ERROR: Unsupported image {path:?}: {error:?}");
+            None
+        }
+    }
+}
+
+// Brightness from 0 to 1, with transparent pixels counting as black
+fn brightness(pixel: &LumaA<u8>) -> f32 {
+    let [luma, alpha] = pixel.0;
+    luma as f32 * alpha as f32 / (255.0 * 255.0)
+}
+
+impl Simulation {
+    pub(crate) fn cycle_import_mode(&mut self) {
+        self.import_mode = self.import_mode.next();
+    }
+
+    pub(crate) fn import_image(&mut self, path: &PathBuf, mode: ImportMode) {
+        match mode {
+            ImportMode::Picture => self.set_picture(path),
+            ImportMode::Heightmap => self.set_heightmap(path),
+            ImportMode::Mask => self.set_mask(path),
+        }
+    }
+
+    // Rock up to the height of each column with Sand on top, and Water over any ground below the
+    // water line of the world settings
+    fn set_heightmap(&mut self, path: &PathBuf) {
+        let Some(img) = open_image(path) else {
+            return;
+        };
+        // Squashing the image to a single row averages each column
+        let heights = imageops::resize(
+            &img.to_luma_alpha8(),
+            self.width as u32,
+            1,
+            imageops::FilterType::Triangle,
+        );
+        let soil = (self.height / 40).max(2);
+        let water_line = (self.height as f32 * self.world.lakes) as usize;
+
+        self.begin_edit();
+        self.record_all_cells();
+        for x in 0..self.width {
+            let ground = (brightness(heights.get_pixel(x as u32, 0)) * self.height as f32) as usize;
+            for height in 0..self.height {
+                let material = if height + soil < ground {
+                    Material::Rock
+                } else if height < ground {
+                    Material::Sand
+                } else if height < water_line {
+                    Material::Water
+                } else {
+                    Material::Air
+                };
+                let idx = (self.height - 1 - height) * self.width + x;
+                self.grid[idx].set_material(material, &self.properties);
+            }
+        }
+        self.show_materials = true;
+        self.end_edit();
+    }
+
+    fn set_mask(&mut self, path: &PathBuf) {
+        let Some(img) = open_image(path) else {
+            return;
+        };
+        let mask = imageops::resize(
+            &img.to_luma_alpha8(),
+            self.width as u32,
+            self.height as u32,
+            imageops::FilterType::Triangle,
+        );
+
+        // Only ever paints the selected material, whatever the brush is set to insert, so that a
+        // large mask can't scatter thousands of sources or sinks
+        self.begin_edit();
+        for (x, y, pixel) in mask.enumerate_pixels() {
+            if brightness(pixel) > 0.5 {
+                let idx = y as usize * self.width + x as usize;
+                self.record_cell(idx);
+                self.grid[idx].set_material(self.material, &self.properties);
+            }
+        }
+        self.show_materials = true;
+        self.end_edit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InsertMode;
+    use image::{GrayImage, Luma};
+
+    // A mask with only its left column lit
+    fn left_column_mask(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(name);
+        GrayImage::from_fn(4, 4, |x, _| Luma([if x == 0 { 255 } else { 0 }]))
+            .save(&path)
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn masks_paint_the_selected_material() {
+        let path = left_column_mask("particles_mask_material.png");
+        let mut simulation = Simulation::empty(4, 4);
+        simulation.set_material(Material::Sand, InsertMode::Material);
+        simulation.import_image(&path, ImportMode::Mask);
+        std::fs::remove_file(&path).unwrap();
+
+        let sand: Vec<usize> = (0..16)
+            .filter(|&idx| simulation.grid[idx].material == Material::Sand)
+            .collect();
+        assert_eq!(sand, vec![0, 4, 8, 12]);
+    }
+
+    #[test]
+    fn masks_add_no_sources_in_source_mode() {
+        let path = left_column_mask("particles_mask_source.png");
+        let mut simulation = Simulation::empty(4, 4);
+        simulation.set_material(Material::Water, InsertMode::Source);
+        simulation.import_image(&path, ImportMode::Mask);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(simulation.sources.is_empty());
+        let water: Vec<usize> = (0..16)
+            .filter(|&idx| simulation.grid[idx].material == Material::Water)
+            .collect();
+        assert_eq!(water, vec![0, 4, 8, 12]);
+    }
+}
@@ -1,5 +1,8 @@
+use bevy::diagnostic::{DiagnosticsStore, FrameTimeDiagnosticsPlugin};
+use bevy::input::mouse::MouseWheel;
 use bevy::prelude::*;
-use bevy::window::PrimaryWindow;
+use bevy::window::{CursorLeft, PrimaryWindow, WindowFocused};
+use bevy_inspector_egui::bevy_egui::{EguiContexts, EguiPlugin};
 use bevy_pixel_buffer::prelude::*;
 use core::f32;
 use std::cmp::min;
@@ -7,33 +10,105 @@ use image;
 use image::Pixel;
 use rand::prelude::*;
 use rand::seq::SliceRandom;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
+mod bindings;
+mod boundary;
+mod brush;
+mod clipboard;
+mod config;
+mod fill;
+mod generate;
+mod history;
+mod import;
+mod properties;
+mod resize;
+mod rigid;
+mod source;
+mod stats;
+mod support;
+mod tools;
+mod ui;
+mod view;
+
+use bindings::{Action, KeyBindings};
+use boundary::{Boundaries, Boundary};
+use brush::Brush;
+use clipboard::Clipboard;
+use config::Config;
+use fill::Fill;
+use generate::WorldSettings;
+#[cfg(test)]
+use generate::Generator;
+use history::History;
+use import::ImportMode;
+use properties::Properties;
+use resize::{ResizeMode, MIN_WINDOW_SIZE};
+use source::Source;
+use stats::Statistics;
+use tools::Tool;
+use view::View;
+
+// Defaults for when the config file and command line don't say otherwise
 const GRID_WIDTH: usize = 400;
 const GRID_HEIGHT: usize = 200;
 const MIN_PIXEL_SIZE: usize = 2;
+// Larger worlds are shrunk to fit and zoomed into
+const MAX_WINDOW_WIDTH: usize = 1600;
+const MAX_WINDOW_HEIGHT: usize = 1000;
+const EXPLOSION_RADIUS: i32 = 8;
+const EXPLOSION_ENERGY: usize = 10;
 
 // Order is important - lighter at the top
-#[derive(Debug, Clone, Copy, PartialEq, EnumIter)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumIter)]
 enum Material {
     Fire,
     Gas,
     Air,
+    Wood,
     Oil,
     Water,
     Sand,
+    Brick,
     Rock,
 }
 
+impl Material {
+    fn is_solid(&self) -> bool {
+        matches!(self, Material::Wood | Material::Brick | Material::Rock)
+    }
+
+    // Single character names, matching the keys that select each material
+    fn code(&self) -> char {
+        match self {
+            Material::Fire => 'f',
+            Material::Gas => 'g',
+            Material::Air => 'a',
+            Material::Wood => 'd',
+            Material::Oil => 'o',
+            Material::Water => 'w',
+            Material::Sand => 's',
+            Material::Brick => 'b',
+            Material::Rock => 'r',
+        }
+    }
+
+    fn from_code(code: char) -> Option<Material> {
+        Material::iter().find(|material| material.code() == code)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum InsertMode {
     Material,
     Source,
+    Sink,
 }
 
-fn choose_random_material(rng: &mut ThreadRng) -> Material {
+fn choose_random_material(rng: &mut impl Rng) -> Material {
     match rng.gen_range(0..6) {
         0 => Material::Gas,
         1 => Material::Air,
@@ -44,12 +119,17 @@ fn choose_random_material(rng: &mut ThreadRng) -> Material {
     }
 }
 
+#[derive(Clone)]
 struct Particle {
     material: Material,
     alpha: f32,
     energy: usize,
     density: f32,
     viscosity: f32,
+    strength: usize,
+    // Connected to the ground or a wall through a chain of solids, as of the last tick
+    supported: bool,
+    velocity: IVec2,
     color: Color,
 }
 
@@ -61,58 +141,57 @@ impl Default for Particle {
             energy: 0,
             density: 1.0,
             viscosity: 1.0,
+            strength: 0,
+            supported: false,
+            velocity: IVec2::ZERO,
             color: Color::srgba(0.0, 0.0, 0.0, 0.0),
         }
     }
 }
 
 impl Particle {
-    fn new(material: Material, alpha: f32) -> Self {
+    fn new(material: Material, alpha: f32, properties: &Properties) -> Self {
         let mut particle = Particle::default();
         particle.alpha = alpha;
-        particle.set_material(material);
-        particle.color = get_material_color(material, alpha);
+        particle.set_material(material, properties);
+        particle.color = properties.color(material, alpha);
         particle
     }
 
-    fn set_material(&mut self, material: Material) {
+    fn set_material(&mut self, material: Material, properties: &Properties) {
+        let properties = properties.get(material);
         self.material = material;
-        self.energy = match material {
-            Material::Gas => 10,
-            Material::Oil => 50,
-            _ => 0,
-        };
-        self.density = match material {
-            Material::Fire => 0.1,
-            Material::Gas => 0.1,
-            Material::Air => 0.3,
-            Material::Oil => 0.9,
-            Material::Water => 1.0,
-            Material::Sand => 1.5,
-            Material::Rock => 2.0,
-        };
-        self.viscosity = match material {
-            Material::Fire => 10.0,
-            Material::Gas => 6.0,
-            Material::Air => 5.0,
-            Material::Water => 4.0,
-            Material::Oil => 4.0,
-            Material::Sand => 1.0,
-            Material::Rock => 0.0,
-        };
+        self.velocity = IVec2::ZERO;
+        self.energy = properties.energy;
+        self.density = properties.density;
+        self.viscosity = properties.viscosity;
+        self.strength = properties.strength;
     }
 }
 
-fn choose_alpha(rng: &mut ThreadRng) -> f32 {
+fn choose_alpha(rng: &mut impl Rng) -> f32 {
     rng.gen_range(0..=100) as f32 / 100.0
 }
 
-struct Source {
-    material: Material,
-    rate: usize,
-    last_inserted: usize,
+#[derive(Clone)]
+struct Sink {
+    // Only remove this material, or anything when None
+    filter: Option<Material>,
+    consumed: HashMap<Material, usize>,
 }
 
+#[derive(Component)]
+struct SinkCounter;
+
+#[derive(Component)]
+struct SourceInspector;
+
+#[derive(Component)]
+struct CellInspector;
+
+#[derive(Component)]
+struct Hud;
+
 #[derive(Resource)]
 struct Simulation {
     width: usize,
@@ -120,104 +199,220 @@ struct Simulation {
     height: usize,
     max_height: usize,
     pixel_size: usize,
+    resize_mode: ResizeMode,
+    view: View,
+    // Space added to the world is Air rather than random materials
+    fill_with_air: bool,
+    // How the world was last generated
+    world: WorldSettings,
+    // How images dropped onto the window are read, unless a modifier key says otherwise
+    import_mode: ImportMode,
     grid: Vec<Particle>,
     order: Vec<usize>,
     sources: HashMap<usize, Source>,
+    source_template: Source,
+    selected_source: Option<usize>,
+    sources_paused: bool,
+    sinks: HashMap<usize, Sink>,
     material: Material,
     insert_mode: InsertMode,
     insert_rate: usize,
     paused: bool,
+    tick: usize,
+    // Run a single tick while paused
+    step: bool,
     show_materials: bool,
+    show_panel: bool,
+    show_properties: bool,
+    show_statistics: bool,
+    show_help: bool,
+    show_fill: bool,
+    // Mixture used by the fill window
+    fill: Fill,
+    // Grid cell under the mouse, if it is over the window
+    cursor: Option<(i32, i32)>,
+    brush: Brush,
+    brush_outline: HashSet<usize>,
+    // Last cell painted by the stroke in progress
+    stroke_end: Option<(i32, i32)>,
+    tool: Tool,
+    // Where the mouse was pressed for the shape being dragged out
+    drag_start: Option<(i32, i32)>,
+    preview: HashMap<usize, Color>,
+    // Corners of the selected rectangle
+    selection: Option<(i32, i32, i32, i32)>,
+    clipboard: Option<Clipboard>,
+    // Name of the stamp last placed from the stamps directory
+    stamp: Option<String>,
+    // Name the next stamp is saved under
+    stamp_name: String,
+    // Edits clear cells back to Air instead of inserting
+    erasing: bool,
+    // Edits leave anything other than Air alone
+    only_over_air: bool,
+    boundaries: Boundaries,
+    outflow: HashMap<Material, usize>,
+    // Movement budget each cell used up during the last tick
+    moved: HashMap<usize, f32>,
+    void: Particle,
+    history: History,
+    statistics: Statistics,
+    // Density, viscosity, energy and colour of each material, as tuned
+    properties: Properties,
+    // Seeded so that a run can be repeated
+    rng: StdRng,
 }
 
 impl Simulation {
-    fn new(width: usize, height: usize, pixel_size: usize) -> Self {
-        let mut rng = rand::thread_rng();
-        let mut rng_alpha = rand::thread_rng();
-        let grid = (0..width * height)
-            .map(|_| choose_random_material(&mut rng))
-            .map(|m| Particle::new(m, choose_alpha(&mut rng_alpha)))
-            .collect();
+    fn new(
+        width: usize,
+        height: usize,
+        pixel_size: usize,
+        seed: Option<u64>,
+        world: WorldSettings,
+        properties: Properties,
+    ) -> Self {
+        let mut rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let world = WorldSettings {
+            seed: rng.gen(),
+            ..world
+        };
+        let grid = generate::generate_grid(&world, width, height, &properties);
         let mut order: Vec<usize> = (0..width * height).map(|v| v).collect();
         order.shuffle(&mut rng);
 
         Self {
-            max_width: width*pixel_size,
+            max_width: (width*pixel_size).min(MAX_WINDOW_WIDTH),
             width,
-            max_height: height*pixel_size,
+            max_height: (height*pixel_size).min(MAX_WINDOW_HEIGHT),
             height,
             pixel_size,
+            resize_mode: ResizeMode::Grow,
+            view: View::default(),
+            fill_with_air: true,
+            world,
+            import_mode: ImportMode::Picture,
             grid,
             order,
             sources: HashMap::new(),
+            source_template: Source::new(Material::Rock, 5),
+            selected_source: None,
+            sources_paused: false,
+            sinks: HashMap::new(),
             material: Material::Rock,
             insert_mode: InsertMode::Material,
             insert_rate: 5,
             paused: false,
+            tick: 0,
+            step: false,
             show_materials: true,
+            show_panel: true,
+            show_properties: false,
+            show_statistics: false,
+            show_help: false,
+            show_fill: false,
+            fill: Fill::default(),
+            cursor: None,
+            brush: Brush::default(),
+            brush_outline: HashSet::new(),
+            stroke_end: None,
+            tool: Tool::Freehand,
+            drag_start: None,
+            preview: HashMap::new(),
+            selection: None,
+            clipboard: None,
+            stamp: None,
+            stamp_name: String::new(),
+            erasing: false,
+            only_over_air: false,
+            boundaries: Boundaries::walls(),
+            outflow: HashMap::new(),
+            moved: HashMap::new(),
+            void: boundary::void_particle(&properties),
+            history: History::default(),
+            statistics: Statistics::default(),
+            properties,
+            rng,
         }
     }
 
-    fn set_all(&mut self) {
-        for idx in 0..self.width * self.height {
-            self.grid[idx].set_material(self.material);
-        }
+    // A generator for one operation, drawn from the seeded one
+    fn rng(&mut self) -> StdRng {
+        StdRng::seed_from_u64(self.rng.gen())
     }
 
-    fn reset_random(&mut self) {
-        let mut rng = rand::thread_rng();
+    fn set_all(&mut self) {
+        self.begin_edit();
+        self.record_all_cells();
         for idx in 0..self.width * self.height {
-            self.grid[idx].set_material(choose_random_material(&mut rng));
+            self.grid[idx].set_material(self.material, &self.properties);
         }
+        self.end_edit();
     }
 
     fn increase_pixel_size(&mut self) {
         if self.pixel_size < min(self.max_width, self.max_height) - 1 {
             self.pixel_size += 1;
-            self.resize_grid();
+            self.resize_grid(self.pixel_size - 1);
         }
     }
 
     fn decrease_pixel_size(&mut self) {
         if self.pixel_size > MIN_PIXEL_SIZE {
             self.pixel_size -= 1;
-            self.resize_grid();
+            self.resize_grid(self.pixel_size + 1);
         }
     }
 
-    fn resize_grid(&mut self) {
-        let mut rng = rand::thread_rng();
-        let mut rng_alpha = rand::thread_rng();
-        let width = self.max_width / self.pixel_size;
-        let height = self.max_height / self.pixel_size;
-
-        let mut grid = Vec::new();
-        for y in 0..width as i32 {
-            for x in 0..height as i32 {
-                match self.particle_at(x, y) {
-                    Some(particle) => {
-                        grid.push(Particle::new(particle.material, particle.alpha));
-                    }
-                    None => {
-                        let material = choose_random_material(&mut rng);
-                        let alpha = choose_alpha(&mut rng_alpha);
-                        grid.push(Particle::new(material, alpha));
-                    }
-                }
-            }
-        }
-        self.grid = grid;
-        self.order = (0..width * height).map(|v| v).collect();
-        self.order.shuffle(&mut rng);
-        self.width = width;
-        self.height = height;
+    // Keeps the world covering the same area at the new pixel size. The old world is resampled
+    // onto the whole cells it fills, and any part cell left over along the top or right is new
+    // space, filled as for a growing window
+    fn resize_grid(&mut self, old_pixel_size: usize) {
+        let (width, height, pixel_size) = (self.width, self.height, self.pixel_size);
+        let scaled = |n: usize| (n * old_pixel_size / pixel_size).max(1);
+        let covered = |n: usize| (n * old_pixel_size).div_ceil(pixel_size);
+        self.resample_world(scaled(width), scaled(height));
+        self.resize_world(covered(width), covered(height));
     }
 
     fn clear_sources(&mut self) {
+        self.begin_edit();
+        let sources: Vec<usize> = self.sources.keys().copied().collect();
+        for idx in sources {
+            self.record_source(idx);
+        }
         self.sources.clear();
+        self.selected_source = None;
+        self.end_edit();
+    }
+
+    fn clear_sinks(&mut self) {
+        self.begin_edit();
+        let sinks: Vec<usize> = self.sinks.keys().copied().collect();
+        for idx in sinks {
+            self.record_sink(idx);
+        }
+        self.sinks.clear();
+        self.end_edit();
+    }
+
+    // Total of each material removed by all sinks
+    fn sink_totals(&self) -> HashMap<Material, usize> {
+        let mut totals = HashMap::new();
+        for sink in self.sinks.values() {
+            for (material, count) in &sink.consumed {
+                *totals.entry(*material).or_insert(0) += count;
+            }
+        }
+        totals
     }
 
     fn flip(&mut self) {
+        self.begin_edit();
+        self.record_all_cells();
         for y in 0..(self.height / 2) {
             for x in 0..self.width {
                 let top_idx = y * self.width + x;
@@ -225,21 +420,59 @@ impl Simulation {
                 self.grid.swap(top_idx, bottom_idx);
             }
         }
+        self.end_edit();
+    }
+
+    fn explode(&mut self, x: i32, y: i32, radius: i32) {
+        self.begin_edit();
+        let mut rng = self.rng();
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                let distance = ((dx * dx + dy * dy) as f32).sqrt();
+                if distance > radius as f32 {
+                    continue;
+                }
+                if let Some(idx) = self.index_at(x + dx, y + dy) {
+                    self.record_cell(idx);
+                    let material = self.grid[idx].material;
+                    if distance < radius as f32 * 0.6 {
+                        self.grid[idx].set_material(Material::Fire, &self.properties);
+                        self.grid[idx].energy = rng.gen_range(1..=EXPLOSION_ENERGY);
+                    } else if material.is_solid() && rng.gen_ratio(1, 2) {
+                        // The edge of the blast shatters solids into rubble
+                        self.grid[idx].set_material(Material::Sand, &self.properties);
+                    }
+                }
+            }
+        }
+        self.end_edit();
     }
 
     fn toggle_paused(&mut self) {
         self.paused = !self.paused;
     }
 
+    fn step(&mut self) {
+        self.step = true;
+    }
+
     fn toggle_show_materials(&mut self) {
         self.show_materials = !self.show_materials;
     }
 
-    fn set_material(&mut self, material: Material, shift: bool) {
+    fn set_material(&mut self, material: Material, insert_mode: InsertMode) {
         self.material = material;
-        match shift {
-            false => self.insert_mode = InsertMode::Material,
-            true => self.insert_mode = InsertMode::Source,
+        self.insert_mode = insert_mode;
+        self.source_template.materials = vec![(material, 1)];
+
+        // Changing the source material while one is selected edits it in place
+        if insert_mode == InsertMode::Source {
+            if let Some(source) = self
+                .selected_source
+                .and_then(|idx| self.sources.get_mut(&idx))
+            {
+                source.materials = vec![(material, 1)];
+            }
         }
     }
 
@@ -247,9 +480,20 @@ impl Simulation {
         self.insert_rate = 10 - rate;
     }
 
+    // Starts the world from an image, or a stamp pasted into the middle
+    fn load_scene(&mut self, path: &PathBuf) {
+        if path.extension().is_some_and(|ext| ext == clipboard::STAMP_EXTENSION) {
+            self.paste_file(path);
+        } else {
+            self.import_image(path, self.import_mode);
+        }
+    }
+
     fn set_picture(&mut self, path: &PathBuf) {
         match image::ImageReader::open(path).unwrap().decode() {
             Ok(img) => {
+                self.begin_edit();
+                self.record_all_cells();
                 let buffer: image::ImageBuffer<image::Rgba<u8>, Vec<u8>> = image::imageops::resize(
                     &img,
                     self.width.try_into().unwrap(),
@@ -258,12 +502,16 @@ impl Simulation {
                 );
 
                 for (idx, pixel) in buffer.pixels().into_iter().enumerate() {
-                    self.grid[idx].set_material(choose_closest_material(pixel));
+                    self.grid[idx].set_material(
+                        choose_closest_material(pixel, &self.properties),
+                        &self.properties,
+                    );
 
                     // Keep the original image color
                     self.grid[idx].color = pixel_to_color(&pixel);
                 }
                 self.show_materials = false;
+                self.end_edit();
             }
             Err(error) => {
                 println!("ERROR: Unsupported image {path:?}: {error:?}");
@@ -271,23 +519,64 @@ impl Simulation {
         }
     }
 
+    // Makes the current material the one at the given cell
+    fn pick_material(&mut self, x: i32, y: i32) {
+        if let Some(material) = self.material_at(x, y) {
+            self.set_material(material, InsertMode::Material);
+        }
+    }
+
     fn insert(&mut self, x: usize, y: usize) {
         if x < self.width && y < self.height {
             let idx = y * self.width + x;
+            if self.erasing {
+                self.record_cell(idx);
+                self.record_source(idx);
+                self.record_sink(idx);
+                self.grid[idx].set_material(Material::Air, &self.properties);
+                self.sources.remove(&idx);
+                self.sinks.remove(&idx);
+                if self.selected_source == Some(idx) {
+                    self.selected_source = None;
+                }
+                return;
+            }
+            if self.only_over_air && self.grid[idx].material != Material::Air {
+                return;
+            }
+
+            // Only what actually changes is recorded, so painting over nothing leaves no undo step
             match self.insert_mode {
                 InsertMode::Material => {
-                    self.grid[idx].set_material(self.material);
+                    self.record_cell(idx);
+                    self.record_source(idx);
+                    self.record_sink(idx);
+                    self.grid[idx].set_material(self.material, &self.properties);
                     if self.sources.contains_key(&idx) {
                         self.sources.remove(&idx);
                     }
+                    if self.sinks.contains_key(&idx) {
+                        self.sinks.remove(&idx);
+                    }
                 }
                 InsertMode::Source => {
-                    self.sources.insert(
+                    self.record_source(idx);
+                    let mut source = self.source_template.clone();
+                    source.rate = self.insert_rate;
+                    self.sources.insert(idx, source);
+                }
+                InsertMode::Sink => {
+                    // Selecting Air makes a sink that removes anything
+                    let filter = match self.material {
+                        Material::Air => None,
+                        material => Some(material),
+                    };
+                    self.record_sink(idx);
+                    self.sinks.insert(
                         idx,
-                        Source {
-                            material: self.material,
-                            rate: self.insert_rate,
-                            last_inserted: 0,
+                        Sink {
+                            filter,
+                            consumed: HashMap::new(),
                         },
                     );
                 }
@@ -296,31 +585,63 @@ impl Simulation {
     }
 
     fn update(&mut self) {
-        if self.paused {
+        if self.paused && !self.step {
             return;
         }
+        self.step = false;
 
-        let mut rng = rand::thread_rng();
+        let mut rng = self.rng();
         let mut moved = HashMap::new();
+        self.update_support(&mut rng);
+        self.update_rigid_bodies(&mut rng);
         for order_idx in 0..self.order.len() {
             self.update_tile(order_idx, &mut rng, &mut moved);
         }
-        for (idx, source) in &mut self.sources {
-            if source.last_inserted <= 1 {
-                source.last_inserted = source.rate;
-                self.grid[*idx].set_material(source.material);
-            } else {
-                source.last_inserted -= 1;
+        self.moved = moved;
+        self.update_inflow();
+        self.update_sources(&mut rng);
+        for (idx, sink) in &mut self.sinks {
+            let material = self.grid[*idx].material;
+            if material != Material::Air && sink.filter.is_none_or(|filter| filter == material) {
+                *sink.consumed.entry(material).or_insert(0) += 1;
+                self.grid[*idx].set_material(Material::Air, &self.properties);
             }
         }
+        self.tick += 1;
+        self.record_statistics();
+    }
+
+    fn describe_cell(&self, idx: usize) -> String {
+        let particle = &self.grid[idx];
+        let mut description = format!(
+            "Cell ({}, {}): {:?}, alpha {:.2}, energy {}, density {:.2}, viscosity {:.2}",
+            idx % self.width,
+            idx / self.width,
+            particle.material,
+            particle.alpha,
+            particle.energy,
+            particle.density,
+            particle.viscosity,
+        );
+        description += &format!(
+            ", colour {}, moved {:.2}",
+            particle.color.to_srgba().to_hex(),
+            self.moved.get(&idx).copied().unwrap_or(0.0),
+        );
+        if self.sources.contains_key(&idx) {
+            description += " [source]";
+        }
+        if self.sinks.contains_key(&idx) {
+            description += " [sink]";
+        }
+        description
     }
 
     fn particle_at(&self, x: i32, y: i32) -> Option<&Particle> {
-        if x >= 0 && x < (self.width as i32) && y >= 0 && y < (self.height as i32) {
-            let new_idx = (y as usize * self.width) + x as usize;
-            Some(&self.grid[new_idx])
-        } else {
-            None
+        match self.index_at(x, y) {
+            Some(idx) => Some(&self.grid[idx]),
+            None if self.boundary_at(x, y) == Boundary::Open => Some(&self.void),
+            None => None,
         }
     }
 
@@ -374,8 +695,9 @@ impl Simulation {
 
     fn set_on_fire(&mut self, x: i32, y: i32) {
         // Keep other particle properties - just change the material and color
-        let idx = y as usize * self.width + x as usize;
-        self.grid[idx].material = Material::Fire;
+        if let Some(idx) = self.index_at(x, y) {
+            self.grid[idx].material = Material::Fire;
+        }
     }
 
     fn try_set_on_fire(&mut self, x: i32, y: i32) {
@@ -389,7 +711,7 @@ impl Simulation {
     fn update_tile(
         &mut self,
         order_idx: usize,
-        rng: &mut ThreadRng,
+        rng: &mut StdRng,
         moved: &mut HashMap<usize, f32>,
     ) {
         let idx = self.order[order_idx];
@@ -412,7 +734,7 @@ impl Simulation {
                 self.grid[idx].energy -= 1;
             }
             if energy == 0 {
-                self.grid[idx].set_material(Material::Air);
+                self.grid[idx].set_material(Material::Air, &self.properties);
             }
             return;
         } else if energy > 0 && self.neighbour_on_fire(x, y) {
@@ -420,6 +742,22 @@ impl Simulation {
             return;
         }
 
+        // Particles thrown out by a source keep going until they run out of speed
+        let velocity = self.grid[idx].velocity;
+        if velocity != IVec2::ZERO {
+            let step = velocity.signum();
+            self.grid[idx].velocity -= step;
+            if let Some(target) = self.particle_at(x + step.x, y + step.y) {
+                if !target.material.is_solid()
+                    && (target.material == Material::Air || target.density < density)
+                    && self.try_swap(idx, x + step.x, y + step.y, moved, 1)
+                {
+                    return;
+                }
+            }
+            self.grid[idx].velocity = IVec2::ZERO;
+        }
+
         let material = self.material_at(x, y).unwrap();
         let this_viscosity = self.grid[idx].viscosity;
 
@@ -494,10 +832,18 @@ impl Simulation {
         moved: &mut HashMap<usize, f32>,
         distance: usize,
     ) -> bool {
-        let to_idx = to_y as usize * self.width + to_x as usize;
-        if self.grid[from_idx].material == Material::Rock
-            || self.grid[to_idx].material == Material::Rock
-        {
+        let to_idx = match self.index_at(to_x, to_y) {
+            Some(idx) => idx,
+            None => {
+                // Only open edges get this far - whatever goes through is lost
+                if self.grid[from_idx].material.is_solid() {
+                    return false;
+                }
+                self.remove_through_edge(from_idx);
+                return true;
+            }
+        };
+        if self.grid[from_idx].material.is_solid() || self.grid[to_idx].material.is_solid() {
             return false;
         }
 
@@ -512,6 +858,7 @@ impl Simulation {
         let average_density = (self.grid[from_idx].density + self.grid[to_idx].density) / 2.0;
         if from_moved < self.grid[from_idx].viscosity && to_moved < self.grid[to_idx].viscosity {
             self.grid.swap(from_idx, to_idx);
+            self.statistics.swaps += 1;
             moved.insert(from_idx, from_moved + (average_density * distance as f32));
             moved.insert(to_idx, to_moved + (average_density * distance as f32));
             true
@@ -521,14 +868,24 @@ impl Simulation {
     }
 
     fn get_color(&self, pos: UVec2) -> Color {
-        let y: usize = pos.y.try_into().unwrap();
-        let y = y / self.pixel_size;
-        let x: usize = pos.x.try_into().unwrap();
-        let x = x / self.pixel_size;
+        let (x, y) = self.cell_at_screen(pos.as_vec2());
+        let (x, y) = (x as usize, y as usize);
         let idx: usize = y * self.width + x;
-        if idx < self.grid.len() {
-            if self.show_materials {
-                get_material_color(self.grid[idx].material, self.grid[idx].alpha)
+        if x < self.width && idx < self.grid.len() {
+            if self.brush_outline.contains(&idx) {
+                Color::srgba(1.0, 1.0, 1.0, 0.6)
+            } else if let Some(color) = self.preview.get(&idx) {
+                *color
+            } else if self.on_selection_edge(x as i32, y as i32) {
+                Color::srgba(1.0, 1.0, 0.0, 0.8)
+            } else if self.selected_source == Some(idx) {
+                Color::WHITE
+            } else if self.sources.contains_key(&idx) {
+                Color::srgb(1.0, 0.0, 1.0)
+            } else if self.sinks.contains_key(&idx) {
+                Color::srgb(0.0, 1.0, 1.0)
+            } else if self.show_materials {
+                self.properties.color(self.grid[idx].material, self.grid[idx].alpha)
             } else {
                 self.grid[idx].color
             }
@@ -538,23 +895,49 @@ impl Simulation {
     }
 }
 
-fn get_material_color(material: Material, alpha: f32) -> Color {
+// Every cell on the straight line between two points, inclusive
+fn line_points(x0: i32, y0: i32, x1: i32, y1: i32) -> Vec<(i32, i32)> {
+    let dx = (x1 - x0).abs();
+    let dy = -(y1 - y0).abs();
+    let step_x = if x0 < x1 { 1 } else { -1 };
+    let step_y = if y0 < y1 { 1 } else { -1 };
+    let mut error = dx + dy;
+    let (mut x, mut y) = (x0, y0);
+    let mut points = vec![(x, y)];
+    while x != x1 || y != y1 {
+        let error2 = 2 * error;
+        if error2 >= dy {
+            error += dy;
+            x += step_x;
+        }
+        if error2 <= dx {
+            error += dx;
+            y += step_y;
+        }
+        points.push((x, y));
+    }
+    points
+}
+
+fn material_opacity(material: Material, alpha: f32) -> f32 {
     match material {
-        Material::Fire => Color::srgba(1.0, 0.0, 0.0, 0.5 + alpha * 0.5),
-        Material::Gas => Color::srgba(0.2, 0.8, 0.1, 0.5 + alpha * 0.5),
-        Material::Air => Color::srgba(0.0, 0.0, 0.0, alpha * 0.5),
-        Material::Oil => Color::srgba(0.3, 0.3, 0.3, 0.3 + alpha * 0.3),
-        Material::Water => Color::srgba(0.0, 0.0, 1.0, 0.5 + alpha * 0.5),
-        Material::Sand => Color::srgba(1.0, 1.0, 0.1, 0.5 + alpha * 0.5),
-        Material::Rock => Color::srgba(1.0, 1.0, 1.0, 0.3 + alpha * 0.5),
+        Material::Fire => 0.5 + alpha * 0.5,
+        Material::Gas => 0.5 + alpha * 0.5,
+        Material::Air => alpha * 0.5,
+        Material::Wood => 0.7 + alpha * 0.3,
+        Material::Oil => 0.3 + alpha * 0.3,
+        Material::Water => 0.5 + alpha * 0.5,
+        Material::Sand => 0.5 + alpha * 0.5,
+        Material::Brick => 0.6 + alpha * 0.4,
+        Material::Rock => 0.3 + alpha * 0.5,
     }
 }
 
-fn choose_closest_material(pixel: &image::Rgba<u8>) -> Material {
+fn choose_closest_material(pixel: &image::Rgba<u8>, properties: &Properties) -> Material {
     let mut closest_material = Material::Air;
     let mut min = f32::MAX;
     for material in Material::iter() {
-        let color = get_material_color(material, 0.5);
+        let color = properties.color(material, 0.5);
         let diff = color_diff(color, pixel);
         if diff < min {
             min = diff;
@@ -591,26 +974,118 @@ fn color_diff(color: Color, pixel: &image::Rgba<u8>) -> f32 {
     }
 }
 
-fn setup(mut commands: Commands) {
-    let width = GRID_WIDTH;
-    let height = GRID_HEIGHT;
-    let pixel_size = MIN_PIXEL_SIZE;
-
-    let simulation = Simulation::new(width, height, pixel_size);
+fn setup(mut commands: Commands, config: Res<Config>) {
+    let mut simulation = Simulation::new(
+        config.width,
+        config.height,
+        config.pixel_size,
+        config.seed,
+        config.world(),
+        Properties::load(),
+    );
+    simulation.set_material(config.material, InsertMode::Material);
+    simulation.paused = config.paused;
+    simulation.import_mode = config.import_mode;
+    if let Some(path) = &config.scene {
+        simulation.load_scene(path);
+    }
     commands.insert_resource(simulation);
+
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 16.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(5.0),
+            left: Val::Px(5.0),
+            ..default()
+        }),
+        SinkCounter,
+    ));
+
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 16.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(5.0),
+            left: Val::Px(5.0),
+            ..default()
+        }),
+        SourceInspector,
+    ));
+
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 16.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(5.0),
+            right: Val::Px(5.0),
+            ..default()
+        }),
+        CellInspector,
+    ));
+
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 16.0,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(5.0),
+            right: Val::Px(5.0),
+            ..default()
+        })
+        .with_text_justify(JustifyText::Right),
+        Hud,
+    ));
 }
 
 fn main() {
-    let pixel_size: u32 = MIN_PIXEL_SIZE.try_into().unwrap();
-    let x: u32 = GRID_WIDTH.try_into().unwrap();
-    let y: u32 = GRID_HEIGHT.try_into().unwrap();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(error) => {
+            println!("ERROR: {error}");
+            std::process::exit(1);
+        }
+    };
+
+    let pixel_size: u32 = config.pixel_size.try_into().unwrap();
+    let x: u32 = config.width.try_into().unwrap();
+    let y: u32 = config.height.try_into().unwrap();
+    let window_x = (x * pixel_size).min(MAX_WINDOW_WIDTH as u32);
+    let window_y = (y * pixel_size).min(MAX_WINDOW_HEIGHT as u32);
     let size = PixelBufferSize {
-        size: UVec2::new(x * pixel_size, y * pixel_size),
+        size: UVec2::new(window_x, window_y),
         pixel_size: UVec2::new(1, 1),
     };
 
-    let x_f = (x * pixel_size) as f32;
-    let y_f = (y * pixel_size) as f32;
+    let x_f = window_x as f32;
+    let y_f = window_y as f32;
     App::new()
         .add_plugins((
             DefaultPlugins
@@ -619,18 +1094,43 @@ fn main() {
                     primary_window: Some(Window {
                         title: "Particles".into(),
                         resolution: (x_f, y_f).into(),
-                        resizable: false,
+                        resize_constraints: WindowResizeConstraints {
+                            min_width: MIN_WINDOW_SIZE,
+                            min_height: MIN_WINDOW_SIZE,
+                            ..default()
+                        },
                         ..default()
                     }),
                     ..default()
                 })
                 .build(),
             PixelBufferPlugin,
+            EguiPlugin,
+            FrameTimeDiagnosticsPlugin,
         ))
+        .insert_resource(config)
+        .insert_resource(KeyBindings::load())
         .add_systems(Startup, (setup, pixel_buffer_setup(size)))
         .add_systems(
             Update,
-            (update, keyboard_input, mouse_button_input, file_drop),
+            (
+                update,
+                keyboard_input,
+                mouse_button_input,
+                mouse_left_window,
+                file_drop,
+                resize::window_resized,
+                update_sink_counter,
+                update_source_inspector,
+                update_cell_inspector,
+                update_hud,
+                ui::control_panel,
+                ui::material_properties_window,
+                ui::make_room_for_panel,
+                ui::statistics_window,
+                ui::help_window,
+                ui::fill_window,
+            ),
         )
         .run();
 }
@@ -640,107 +1140,283 @@ fn update(mut pb: QueryPixelBuffer, mut simulation: ResMut<Simulation>) {
     pb.frame().per_pixel(|pos, _| simulation.get_color(pos));
 }
 
-fn file_drop(mut evr_dnd: EventReader<FileDragAndDrop>, mut simulation: ResMut<Simulation>) {
+fn update_sink_counter(
+    simulation: Res<Simulation>,
+    mut query: Query<&mut Text, With<SinkCounter>>,
+) {
+    let mut text = query.single_mut();
+    text.sections[0].value = if simulation.sinks.is_empty() {
+        String::new()
+    } else {
+        let mut totals: Vec<(Material, usize)> = simulation.sink_totals().into_iter().collect();
+        totals.sort_by_key(|(material, _)| *material as usize);
+        let counts: Vec<String> = totals
+            .iter()
+            .map(|(material, count)| format!("{material:?} {count}"))
+            .collect();
+        format!("Sinks: {}", counts.join(", "))
+    };
+}
+
+fn update_source_inspector(
+    simulation: Res<Simulation>,
+    mut query: Query<&mut Text, With<SourceInspector>>,
+) {
+    // Sources under the mouse take priority over the selected one
+    let hovered = simulation
+        .cursor
+        .and_then(|(x, y)| simulation.index_at(x, y))
+        .and_then(|idx| simulation.describe_source(idx));
+    let selected = simulation
+        .selected_source
+        .and_then(|idx| simulation.describe_source(idx));
+
+    let mut text = query.single_mut();
+    text.sections[0].value = hovered.or(selected).unwrap_or_default();
+}
+
+fn update_cell_inspector(
+    simulation: Res<Simulation>,
+    mut query: Query<&mut Text, With<CellInspector>>,
+) {
+    let mut text = query.single_mut();
+    text.sections[0].value = simulation
+        .cursor
+        .and_then(|(x, y)| simulation.index_at(x, y))
+        .map(|idx| simulation.describe_cell(idx))
+        .unwrap_or_default();
+}
+
+fn update_hud(
+    simulation: Res<Simulation>,
+    diagnostics: Res<DiagnosticsStore>,
+    time: Res<Time>,
+    // Time and tick count at the start of the current second
+    mut tick_rate: Local<(f32, usize, f32)>,
+    mut query: Query<&mut Text, With<Hud>>,
+) {
+    let (started, first_tick, ticks_per_second) = &mut *tick_rate;
+    let now = time.elapsed_seconds();
+    if now - *started >= 1.0 {
+        *ticks_per_second = (simulation.tick - *first_tick) as f32 / (now - *started);
+        *started = now;
+        *first_tick = simulation.tick;
+    }
+    let fps = diagnostics
+        .get(&FrameTimeDiagnosticsPlugin::FPS)
+        .and_then(|fps| fps.smoothed())
+        .unwrap_or_default();
+
+    let mut text = query.single_mut();
+    text.sections[0].value = format!(
+        "{:?} {:?}, {}, rate {}, {}, {} view\n{fps:.0} fps, {ticks_per_second:.0} ticks/s, tick {}, {}x{}",
+        simulation.material,
+        simulation.insert_mode,
+        simulation.tool.name(),
+        10 - simulation.insert_rate,
+        if simulation.paused { "paused" } else { "running" },
+        if simulation.show_materials {
+            "material"
+        } else {
+            "image"
+        },
+        simulation.tick,
+        simulation.width,
+        simulation.height,
+    );
+}
+
+fn file_drop(
+    mut evr_dnd: EventReader<FileDragAndDrop>,
+    mut simulation: ResMut<Simulation>,
+    keys: Res<ButtonInput<KeyCode>>,
+) {
+    // Shift drops an image in as a heightmap and Ctrl as a mask. The keys are only seen if the
+    // window has focus, which most platforms don't give it while dragging in from elsewhere, so
+    // the panel's Drop images as setting is the dependable way to choose
+    let mode = if keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+        ImportMode::Heightmap
+    } else if keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]) {
+        ImportMode::Mask
+    } else {
+        simulation.import_mode
+    };
     for ev in evr_dnd.read() {
         if let FileDragAndDrop::DroppedFile {
             window: _,
             path_buf,
         } = ev
         {
-            simulation.set_picture(path_buf);
+            simulation.import_image(path_buf, mode);
         }
     }
 }
 
-fn keyboard_input(mut simulation: ResMut<Simulation>, keys: Res<ButtonInput<KeyCode>>) {
-    if keys.just_pressed(KeyCode::Space) {
-        simulation.set_all();
+fn keyboard_input(
+    mut simulation: ResMut<Simulation>,
+    keys: Res<ButtonInput<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    mut contexts: EguiContexts,
+) {
+    // Typing into the control panel shouldn't also drive the simulation
+    if contexts.ctx_mut().wants_keyboard_input() {
+        return;
     }
 
-    let shift = keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
-    if keys.just_pressed(KeyCode::KeyA) {
-        simulation.set_material(Material::Air, shift);
-    }
-    if keys.just_pressed(KeyCode::KeyF) {
-        simulation.set_material(Material::Fire, shift);
-    }
-    if keys.just_pressed(KeyCode::KeyG) {
-        simulation.set_material(Material::Gas, shift);
+    for action in bindings.just_pressed(&keys) {
+        simulation.perform(action);
     }
-    if keys.just_pressed(KeyCode::KeyO) {
-        simulation.set_material(Material::Oil, shift);
-    }
-    if keys.just_pressed(KeyCode::KeyR) {
-        simulation.set_material(Material::Rock, shift);
-    }
-    if keys.just_pressed(KeyCode::KeyS) {
-        simulation.set_material(Material::Sand, shift);
-    }
-    if keys.just_pressed(KeyCode::KeyW) {
-        simulation.set_material(Material::Water, shift);
-    }
-    if keys.just_pressed(KeyCode::KeyC) {
-        simulation.clear_sources();
-    }
-    if keys.just_pressed(KeyCode::KeyU) {
-        simulation.flip();
-    }
-    if keys.just_pressed(KeyCode::KeyP) {
-        simulation.toggle_paused();
-    }
-    if keys.just_pressed(KeyCode::KeyM) {
-        simulation.toggle_show_materials();
-    }
-    if keys.just_pressed(KeyCode::Enter) {
-        simulation.reset_random();
+    // Without a source selected the move keys pan the view for as long as they are held
+    if simulation.selected_source.is_none() {
+        for action in bindings.pressed(&keys) {
+            if let Action::Move(dx, dy) = action {
+                simulation.pan_view_with_keys(Vec2::new(dx as f32, dy as f32));
+            }
+        }
     }
+}
 
-    if keys.just_pressed(KeyCode::BracketLeft) {
-        simulation.decrease_pixel_size();
-    }
-    if keys.just_pressed(KeyCode::BracketRight) {
-        simulation.increase_pixel_size();
+fn mouse_button_input(
+    mut simulation: ResMut<Simulation>,
+    q_windows: Query<&Window, With<PrimaryWindow>>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut scroll: EventReader<MouseWheel>,
+    mut contexts: EguiContexts,
+) {
+    let mut position = q_windows.single().cursor_position();
+    // The control panel gets the mouse when it is over it
+    if contexts.ctx_mut().is_pointer_over_area() {
+        position = None;
+        scroll.clear();
     }
 
-    if keys.just_pressed(KeyCode::Digit1) {
-        simulation.set_insert_rate(1);
-    }
-    if keys.just_pressed(KeyCode::Digit2) {
-        simulation.set_insert_rate(2);
-    }
-    if keys.just_pressed(KeyCode::Digit3) {
-        simulation.set_insert_rate(3);
+    let shift = keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    let ctrl = keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+    let alt = keys.any_pressed([KeyCode::AltLeft, KeyCode::AltRight]);
+    for event in scroll.read() {
+        let notches = event.y.signum();
+        if shift {
+            simulation.change_brush_density(notches * 0.05);
+        } else if ctrl {
+            if let Some(position) = position {
+                simulation.zoom_view(notches, position);
+            }
+        } else {
+            simulation.change_brush_radius(notches as i32);
+        }
     }
-    if keys.just_pressed(KeyCode::Digit4) {
-        simulation.set_insert_rate(4);
+    // Dragging with the middle button moves the view around, while a click picks up a material
+    simulation.drag_view(position.filter(|_| buttons.pressed(MouseButton::Middle)));
+    simulation.cursor = position.map(|position| simulation.cell_at_screen(position));
+    simulation.update_brush_outline();
+
+    // Left paints and right erases, unless Alt is held to pick up a material instead
+    let paint_buttons = [MouseButton::Left, MouseButton::Right];
+    let pressed = !alt && buttons.any_pressed(paint_buttons);
+    let just_pressed = !alt && buttons.any_just_pressed(paint_buttons);
+    if just_pressed {
+        simulation.erasing = buttons.pressed(MouseButton::Right);
     }
-    if keys.just_pressed(KeyCode::Digit5) {
-        simulation.set_insert_rate(5);
+    simulation.only_over_air = ctrl;
+
+    if !pressed {
+        simulation.end_stroke();
     }
-    if keys.just_pressed(KeyCode::Digit6) {
-        simulation.set_insert_rate(6);
+    if let Some((x, y)) = simulation.cursor {
+        if (buttons.just_released(MouseButton::Middle) && !simulation.view.dragged)
+            || (alt && buttons.just_pressed(MouseButton::Left))
+        {
+            simulation.pick_material(x, y);
+        }
+
+        match simulation.tool {
+            Tool::Freehand => {
+                if pressed {
+                    simulation.stroke_to(x, y);
+                }
+            }
+            Tool::FloodFill => {
+                if just_pressed {
+                    simulation.flood_fill(x, y);
+                }
+            }
+            Tool::Paste => {
+                simulation.update_paste_preview(x, y);
+                if just_pressed {
+                    simulation.paste_at(x, y);
+                }
+            }
+            Tool::Line | Tool::Rectangle { .. } | Tool::Ellipse { .. } | Tool::Select => {
+                if just_pressed {
+                    simulation.start_drag(x, y);
+                } else if buttons.any_just_released(paint_buttons) {
+                    simulation.finish_drag(x, y);
+                } else if pressed {
+                    simulation.update_drag(x, y);
+                }
+            }
+        }
     }
-    if keys.just_pressed(KeyCode::Digit7) {
-        simulation.set_insert_rate(7);
+}
+
+// Buttons released outside the window are never seen, so whatever they were doing is finished
+// as soon as the mouse leaves or the window loses focus
+fn mouse_left_window(
+    mut simulation: ResMut<Simulation>,
+    mut left: EventReader<CursorLeft>,
+    mut focused: EventReader<WindowFocused>,
+) {
+    let lost_focus = focused.read().any(|event| !event.focused);
+    if left.read().count() > 0 || lost_focus {
+        simulation.end_stroke();
+        simulation.cancel_drag();
     }
-    if keys.just_pressed(KeyCode::Digit8) {
-        simulation.set_insert_rate(8);
+}
+
+#[cfg(test)]
+impl Simulation {
+    // A world of nothing but Air, the same every time
+    pub(crate) fn empty(width: usize, height: usize) -> Self {
+        let mut simulation = Simulation::new(
+            width,
+            height,
+            1,
+            Some(0),
+            Generator::Noise.settings(0),
+            Properties::default(),
+        );
+        for particle in &mut simulation.grid {
+            particle.set_material(Material::Air, &simulation.properties);
+        }
+        simulation
     }
-    if keys.just_pressed(KeyCode::Digit9) {
-        simulation.set_insert_rate(9);
+
+    pub(crate) fn put(&mut self, x: usize, y: usize, material: Material) {
+        let idx = y * self.width + x;
+        self.grid[idx].set_material(material, &self.properties);
     }
 }
 
-fn mouse_button_input(
-    mut simulation: ResMut<Simulation>,
-    q_windows: Query<&Window, With<PrimaryWindow>>,
-    buttons: Res<ButtonInput<MouseButton>>,
-) {
-    if buttons.pressed(MouseButton::Left) {
-        if let Some(position) = q_windows.single().cursor_position() {
-            let x = position.x as usize / simulation.pixel_size;
-            let y = position.y as usize / simulation.pixel_size;
-            simulation.insert(x, y);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_points_join_both_ends_without_gaps() {
+        let points = line_points(0, 0, 5, 2);
+        assert_eq!(points.first(), Some(&(0, 0)));
+        assert_eq!(points.last(), Some(&(5, 2)));
+        assert_eq!(points.len(), 6);
+        for pair in points.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            assert!((a.0 - b.0).abs() <= 1 && (a.1 - b.1).abs() <= 1);
         }
     }
+
+    #[test]
+    fn line_points_go_backwards_and_stay_put() {
+        assert_eq!(line_points(2, 3, 2, 0), vec![(2, 3), (2, 2), (2, 1), (2, 0)]);
+        assert_eq!(line_points(4, 4, 4, 4), vec![(4, 4)]);
+    }
 }
@@ -0,0 +1,205 @@
+use crate::{material_opacity, Material, Simulation};
+use bevy::prelude::*;
+use std::collections::HashMap;
+use std::fs;
+use strum::IntoEnumIterator;
+
+pub(crate) const PROPERTIES_FILE: &str = "materials.txt";
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct MaterialProperties {
+    pub(crate) density: f32,
+    pub(crate) viscosity: f32,
+    // Fuel a fresh particle burns for
+    pub(crate) energy: usize,
+    // How far a solid can span sideways from whatever is holding it up
+    pub(crate) strength: usize,
+    pub(crate) color: [f32; 3],
+}
+
+// Tuned values for every material, kept by the simulation and handed to whatever creates or
+// draws particles
+#[derive(Clone)]
+pub(crate) struct Properties(HashMap<Material, MaterialProperties>);
+
+fn default_properties(material: Material) -> MaterialProperties {
+    let (density, viscosity, energy, strength, color) = match material {
+        Material::Fire => (0.1, 10.0, 0, 0, [1.0, 0.0, 0.0]),
+        Material::Gas => (0.1, 6.0, 10, 0, [0.2, 0.8, 0.1]),
+        Material::Air => (0.3, 5.0, 0, 0, [0.0, 0.0, 0.0]),
+        Material::Wood => (0.7, 0.0, 80, 25, [0.55, 0.35, 0.15]),
+        Material::Oil => (0.9, 4.0, 50, 0, [0.3, 0.3, 0.3]),
+        Material::Water => (1.0, 4.0, 0, 0, [0.0, 0.0, 1.0]),
+        Material::Sand => (1.5, 1.0, 0, 0, [1.0, 1.0, 0.1]),
+        Material::Brick => (1.8, 0.0, 0, 8, [0.7, 0.25, 0.15]),
+        Material::Rock => (2.0, 0.0, 0, 12, [1.0, 1.0, 1.0]),
+    };
+    MaterialProperties {
+        density,
+        viscosity,
+        energy,
+        strength,
+        color,
+    }
+}
+
+impl Default for Properties {
+    fn default() -> Self {
+        Properties(
+            Material::iter()
+                .map(|material| (material, default_properties(material)))
+                .collect(),
+        )
+    }
+}
+
+impl Properties {
+    pub(crate) fn get(&self, material: Material) -> MaterialProperties {
+        self.0[&material]
+    }
+
+    pub(crate) fn color(&self, material: Material, alpha: f32) -> Color {
+        let [red, green, blue] = self.get(material).color;
+        Color::srgba(red, green, blue, material_opacity(material, alpha))
+    }
+
+    // Picks up values tuned in an earlier run, before any particles are made
+    pub(crate) fn load() -> Properties {
+        let mut properties = Properties::default();
+        let Ok(text) = fs::read_to_string(PROPERTIES_FILE) else {
+            return properties;
+        };
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            match parse_properties(line) {
+                Ok((material, values)) => {
+                    properties.0.insert(material, values);
+                }
+                Err(error) => println!("ERROR: Invalid {PROPERTIES_FILE:?}: {error}"),
+            }
+        }
+        properties
+    }
+
+    pub(crate) fn save(&self) {
+        let mut text = String::new();
+        for material in Material::iter() {
+            let properties = self.get(material);
+            let [red, green, blue] = properties.color;
+            text += &format!(
+                "{} {} {} {} {} {red} {green} {blue}\n",
+                material.code(),
+                properties.density,
+                properties.viscosity,
+                properties.energy,
+                properties.strength,
+            );
+        }
+        if let Err(error) = fs::write(PROPERTIES_FILE, text) {
+            println!("ERROR: Unable to save {PROPERTIES_FILE:?}: {error:?}");
+        }
+    }
+}
+
+impl Simulation {
+    // Changes a material and brings the particles already made of it into line
+    pub(crate) fn set_properties(&mut self, material: Material, properties: MaterialProperties) {
+        let old = self.properties.get(material);
+        self.properties.0.insert(material, properties);
+
+        let [red, green, blue] = old.color;
+        for particle in &mut self.grid {
+            if particle.material != material {
+                continue;
+            }
+            if properties.density != old.density {
+                particle.density = properties.density;
+            }
+            if properties.viscosity != old.viscosity {
+                particle.viscosity = properties.viscosity;
+            }
+            if properties.energy != old.energy {
+                particle.energy = properties.energy;
+            }
+            if properties.strength != old.strength {
+                particle.strength = properties.strength;
+            }
+            // Leave colours loaded from an image alone
+            let old_color =
+                Color::srgba(red, green, blue, material_opacity(material, particle.alpha));
+            if properties.color != old.color && particle.color == old_color {
+                particle.color = self.properties.color(material, particle.alpha);
+            }
+        }
+    }
+
+    pub(crate) fn reset_properties(&mut self) {
+        for material in Material::iter() {
+            self.set_properties(material, default_properties(material));
+        }
+    }
+}
+
+fn parse_properties(line: &str) -> Result<(Material, MaterialProperties), String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [code, density, viscosity, energy, strength, red, green, blue] = fields[..] else {
+        return Err(format!("expected 8 fields in {line:?}"));
+    };
+    let material = code
+        .chars()
+        .next()
+        .and_then(Material::from_code)
+        .ok_or(format!("unknown material {code:?}"))?;
+    let number = |value: &str| {
+        value
+            .parse::<f32>()
+            .map_err(|_| format!("invalid number {value:?}"))
+    };
+    let energy = energy
+        .parse()
+        .map_err(|_| format!("invalid energy {energy:?}"))?;
+    let strength = strength
+        .parse()
+        .map_err(|_| format!("invalid strength {strength:?}"))?;
+    let properties = MaterialProperties {
+        density: number(density)?,
+        viscosity: number(viscosity)?,
+        energy,
+        strength,
+        color: [number(red)?, number(green)?, number(blue)?],
+    };
+    Ok((material, properties))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_lines_parse_back() {
+        let (material, properties) = parse_properties("w 1.2 3.5 7 4 0.1 0.2 0.3").unwrap();
+        assert_eq!(material, Material::Water);
+        assert_eq!(properties.density, 1.2);
+        assert_eq!(properties.viscosity, 3.5);
+        assert_eq!(properties.energy, 7);
+        assert_eq!(properties.strength, 4);
+        assert_eq!(properties.color, [0.1, 0.2, 0.3]);
+        assert!(parse_properties("w 1.2 3.5").is_err());
+        assert!(parse_properties("? 1 1 1 1 1 1 1").is_err());
+    }
+
+    #[test]
+    fn tuning_changes_particles_already_made() {
+        let mut simulation = Simulation::empty(2, 1);
+        simulation.put(0, 0, Material::Water);
+        let tuned = MaterialProperties {
+            density: 1.1,
+            ..simulation.properties.get(Material::Water)
+        };
+        simulation.set_properties(Material::Water, tuned);
+        assert_eq!(simulation.grid[0].density, 1.1);
+        assert_eq!(simulation.grid[1].density, 0.3);
+
+        simulation.reset_properties();
+        assert_eq!(simulation.grid[0].density, 1.0);
+    }
+}
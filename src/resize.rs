@@ -0,0 +1,283 @@
+use crate::{choose_alpha, choose_random_material, Material, Particle, Simulation};
+use bevy::prelude::*;
+use bevy::window::WindowResized;
+use bevy_pixel_buffer::prelude::*;
+use rand::prelude::*;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+// The window can't be made smaller than this
+pub(crate) const MIN_WINDOW_SIZE: f32 = 100.0;
+
+// What happens to the world when the window changes size
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ResizeMode {
+    // More or fewer cells, keeping what is there sat on the bottom of the window
+    Grow,
+    // The same cells stretched over the new window
+    Scale,
+}
+
+// Moves the entries of an index-keyed map to the same cells in a grid of a new size, dropping any
+// that no longer fit
+fn move_entries<T>(
+    map: HashMap<usize, T>,
+    new_index: impl Fn(usize) -> Option<usize>,
+) -> HashMap<usize, T> {
+    map.into_iter()
+        .filter_map(|(idx, value)| Some((new_index(idx)?, value)))
+        .collect()
+}
+
+impl Simulation {
+    pub(crate) fn cycle_resize_mode(&mut self) {
+        self.resize_mode = match self.resize_mode {
+            ResizeMode::Grow => ResizeMode::Scale,
+            ResizeMode::Scale => ResizeMode::Grow,
+        };
+    }
+
+    // Crops or extends the world, keeping the bottom row at the bottom
+    pub(crate) fn resize_world(&mut self, width: usize, height: usize) {
+        if (width, height) == (self.width, self.height) {
+            return;
+        }
+        let mut rng = self.rng();
+        let old_width = self.width;
+        let old_height = self.height;
+        let dy = height as i32 - old_height as i32;
+        let new_index = |idx: usize| {
+            let x = idx % old_width;
+            let y = (idx / old_width) as i32 + dy;
+            (x < width && y >= 0 && y < height as i32).then(|| y as usize * width + x)
+        };
+
+        let mut grid = vec![None; width * height];
+        for (idx, particle) in std::mem::take(&mut self.grid).into_iter().enumerate() {
+            if let Some(new_idx) = new_index(idx) {
+                grid[new_idx] = Some(particle);
+            }
+        }
+
+        let fill_with_air = self.fill_with_air;
+        let grid = grid
+            .into_iter()
+            .map(|particle| {
+                particle.unwrap_or_else(|| {
+                    let material = if fill_with_air {
+                        Material::Air
+                    } else {
+                        choose_random_material(&mut rng)
+                    };
+                    Particle::new(material, choose_alpha(&mut rng), &self.properties)
+                })
+            })
+            .collect();
+        self.replace_grid(grid, width, height, new_index);
+    }
+
+    // Resamples the world at a new resolution, taking the most common material in each block of
+    // cells when shrinking and repeating cells when growing
+    pub(crate) fn resample_world(&mut self, width: usize, height: usize) {
+        if (width, height) == (self.width, self.height) {
+            return;
+        }
+        let old_width = self.width;
+        let old_height = self.height;
+        // The old cells covered by a new one along one axis - always at least one
+        let span = |n: usize, old: usize, new: usize| {
+            let start = n * old / new;
+            start..((n + 1) * old / new).max(start + 1)
+        };
+
+        let mut grid = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                // How many of each material, and the first cell found of it
+                let mut counts: HashMap<Material, (usize, usize)> = HashMap::new();
+                for old_y in span(y, old_height, height) {
+                    for old_x in span(x, old_width, width) {
+                        let idx = old_y * old_width + old_x;
+                        counts.entry(self.grid[idx].material).or_insert((0, idx)).0 += 1;
+                    }
+                }
+                let (_, idx) = counts
+                    .into_values()
+                    .max_by_key(|&(count, idx)| (count, Reverse(idx)))
+                    .unwrap();
+                grid.push(self.grid[idx].clone());
+            }
+        }
+
+        let new_index = |idx: usize| {
+            let x = idx % old_width * width / old_width;
+            let y = idx / old_width * height / old_height;
+            Some(y * width + x)
+        };
+        self.replace_grid(grid, width, height, new_index);
+    }
+
+    // Swaps in a grid of a new size, taking sources and sinks along to their new cells
+    fn replace_grid(
+        &mut self,
+        grid: Vec<Particle>,
+        width: usize,
+        height: usize,
+        new_index: impl Fn(usize) -> Option<usize>,
+    ) {
+        let mut rng = self.rng();
+        self.grid = grid;
+        self.sources = move_entries(std::mem::take(&mut self.sources), &new_index);
+        self.sinks = move_entries(std::mem::take(&mut self.sinks), &new_index);
+        self.selected_source = self.selected_source.and_then(&new_index);
+        self.width = width;
+        self.height = height;
+        self.order = (0..width * height).collect();
+        self.order.shuffle(&mut rng);
+        self.moved.clear();
+        self.brush_outline.clear();
+        self.preview.clear();
+        self.selection = None;
+        self.drag_start = None;
+        self.stroke_end = None;
+        self.clamp_view();
+        self.clear_history();
+    }
+
+    fn fit_window(&mut self, window_width: usize, window_height: usize) {
+        // Growing keeps each cell the same size on screen
+        let width = (window_width * self.width / self.max_width).max(1);
+        let height = (window_height * self.height / self.max_height).max(1);
+        self.max_width = window_width;
+        self.max_height = window_height;
+        if self.resize_mode == ResizeMode::Grow {
+            self.resize_world(width, height);
+        }
+        self.clamp_view();
+    }
+}
+
+// The size to fit the world to, or None when the window has been minimised. Minimising reports
+// a size of zero, which would otherwise shrink the world to nothing and throw away its history
+fn window_size(width: f32, height: f32) -> Option<(usize, usize)> {
+    if width < 1.0 || height < 1.0 {
+        return None;
+    }
+    Some((
+        width.max(MIN_WINDOW_SIZE) as usize,
+        height.max(MIN_WINDOW_SIZE) as usize,
+    ))
+}
+
+pub(crate) fn window_resized(
+    mut events: EventReader<WindowResized>,
+    mut simulation: ResMut<Simulation>,
+    mut buffers: Query<&mut PixelBuffer>,
+) {
+    let Some(event) = events.read().last() else {
+        return;
+    };
+    let Some((width, height)) = window_size(event.width, event.height) else {
+        return;
+    };
+    if (width, height) == (simulation.max_width, simulation.max_height) {
+        return;
+    }
+
+    simulation.fit_window(width, height);
+    for mut buffer in &mut buffers {
+        buffer.size.size = UVec2::new(width as u32, height as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::Source;
+
+    fn materials(simulation: &Simulation) -> Vec<Material> {
+        simulation.grid.iter().map(|cell| cell.material).collect()
+    }
+
+    #[test]
+    fn resampling_keeps_the_most_common_material() {
+        let mut simulation = Simulation::empty(4, 2);
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (3, 1)] {
+            simulation.put(x, y, Material::Rock);
+        }
+        simulation.resample_world(2, 1);
+        assert_eq!(materials(&simulation), vec![Material::Rock, Material::Air]);
+
+        simulation.resample_world(4, 2);
+        assert_eq!((simulation.width, simulation.height), (4, 2));
+        assert_eq!(
+            materials(&simulation),
+            [[Material::Rock; 2], [Material::Air; 2]].concat().repeat(2)
+        );
+    }
+
+    #[test]
+    fn resampling_maps_every_cell() {
+        let row = [Material::Rock, Material::Sand, Material::Water];
+        let mut simulation = Simulation::empty(3, 2);
+        for (x, &material) in row.iter().enumerate() {
+            simulation.put(x, 0, material);
+        }
+
+        // Each cell becomes a 2 by 2 block
+        simulation.resample_world(6, 4);
+        for y in 0..4 {
+            for x in 0..6 {
+                let expected = if y < 2 { row[x / 2] } else { Material::Air };
+                assert_eq!(simulation.grid[y * 6 + x].material, expected, "({x}, {y})");
+            }
+        }
+
+        // Each 2 by 2 block becomes one cell again
+        simulation.resample_world(3, 2);
+        assert_eq!(materials(&simulation), [row, [Material::Air; 3]].concat());
+    }
+
+    #[test]
+    fn space_left_over_by_resampling_is_filled_with_air() {
+        let mut simulation = Simulation::empty(3, 3);
+        for idx in 0..9 {
+            simulation.put(idx % 3, idx / 3, Material::Rock);
+        }
+        simulation.fill_with_air = true;
+
+        // Three cells of 3 pixels make four and a half cells of 2 pixels
+        simulation.pixel_size = 2;
+        simulation.resize_grid(3);
+        assert_eq!((simulation.width, simulation.height), (5, 5));
+        for y in 0..5 {
+            for x in 0..5 {
+                let expected = if y == 0 || x == 4 {
+                    Material::Air
+                } else {
+                    Material::Rock
+                };
+                assert_eq!(simulation.grid[y * 5 + x].material, expected, "({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn minimising_leaves_the_world_alone() {
+        assert_eq!(window_size(0.0, 0.0), None);
+        assert_eq!(window_size(800.0, 0.0), None);
+        assert_eq!(window_size(40.0, 300.0), Some((100, 300)));
+        assert_eq!(window_size(800.0, 600.0), Some((800, 600)));
+    }
+
+    #[test]
+    fn sources_follow_their_cells() {
+        let mut simulation = Simulation::empty(4, 4);
+        simulation
+            .sources
+            .insert(3 * 4 + 2, Source::new(Material::Water, 1));
+        simulation.resample_world(2, 2);
+        // From the third column of the bottom row to the second column of the bottom row
+        assert!(simulation.sources.contains_key(&3));
+    }
+}
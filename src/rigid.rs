@@ -0,0 +1,309 @@
+use crate::{Particle, Simulation};
+use rand::prelude::*;
+
+// Bodies fall every tick through anything at least as runny as Air
+const FREE_FALL_VISCOSITY: f32 = 5.0;
+
+// A connected cluster of solids that moves as a single unit
+struct RigidBody {
+    label: usize,
+    cells: Vec<usize>,
+    anchored: bool,
+}
+
+impl Simulation {
+    pub(crate) fn update_rigid_bodies(&mut self, rng: &mut StdRng) {
+        let (mut bodies, labels) = self.find_rigid_bodies();
+
+        // Move the lowest bodies first so that stacked bodies fall together
+        bodies.sort_by_key(|body| std::cmp::Reverse(body.cells.iter().max().copied()));
+
+        for body in bodies.iter().filter(|body| !body.anchored) {
+            if let Some(resistance) = self.rigid_body_resistance(body, &labels, 0, 1) {
+                if rng.gen::<f32>() * FREE_FALL_VISCOSITY < resistance {
+                    self.move_rigid_body(body, &labels, 0, 1);
+                }
+                continue;
+            }
+
+            // Resting on something - topple over the edge of the support the weight overhangs,
+            // or slide diagonally off it if there is no room to swing
+            if let Some((dx, pivot)) = self.rigid_body_overhang(body, &labels) {
+                if !self.tip_rigid_body(body, &labels, dx, pivot)
+                    && self.rigid_body_resistance(body, &labels, dx, 1).is_some()
+                {
+                    self.move_rigid_body(body, &labels, dx, 1);
+                }
+            }
+        }
+    }
+
+    fn find_rigid_bodies(&self) -> (Vec<RigidBody>, Vec<usize>) {
+        let mut labels = vec![usize::MAX; self.grid.len()];
+        let mut bodies = Vec::new();
+        for start in 0..self.grid.len() {
+            if labels[start] != usize::MAX || !self.grid[start].material.is_solid() {
+                continue;
+            }
+
+            let mut body = RigidBody {
+                label: bodies.len(),
+                cells: Vec::new(),
+                anchored: false,
+            };
+            labels[start] = body.label;
+            let mut stack = vec![start];
+            while let Some(idx) = stack.pop() {
+                body.cells.push(idx);
+                let x = (idx % self.width) as i32;
+                let y = (idx / self.width) as i32;
+
+                // Anything connected to the ground or a wall is held in place by it
+                if self.grid[idx].supported {
+                    body.anchored = true;
+                }
+
+                for (nx, ny) in [(x, y - 1), (x, y + 1), (x - 1, y), (x + 1, y)] {
+                    if let Some(n_idx) = self.index_at(nx, ny) {
+                        if self.grid[n_idx].material.is_solid() && labels[n_idx] == usize::MAX {
+                            labels[n_idx] = body.label;
+                            stack.push(n_idx);
+                        }
+                    }
+                }
+            }
+            bodies.push(body);
+        }
+        (bodies, labels)
+    }
+
+    // Returns the average viscosity of everything the body would displace by moving,
+    // or None if it is blocked
+    fn rigid_body_resistance(
+        &self,
+        body: &RigidBody,
+        labels: &[usize],
+        dx: i32,
+        dy: i32,
+    ) -> Option<f32> {
+        let density = self.rigid_body_density(body);
+        let mut viscosity = 0.0;
+        let mut displaced = 0;
+        for &idx in &body.cells {
+            let x = (idx % self.width) as i32 + dx;
+            let y = (idx / self.width) as i32 + dy;
+            let target = self.index_at(x, y)?;
+            if labels[target] == body.label {
+                continue;
+            }
+            let particle = &self.grid[target];
+            if particle.material.is_solid() || particle.density >= density {
+                return None;
+            }
+            viscosity += particle.viscosity;
+            displaced += 1;
+        }
+        Some(viscosity / displaced.max(1) as f32)
+    }
+
+    // Returns the direction to tip in and the corner of the support to tip about,
+    // if all the support is to one side of the centre
+    fn rigid_body_overhang(&self, body: &RigidBody, labels: &[usize]) -> Option<(i32, (i32, i32))> {
+        let density = self.rigid_body_density(body);
+        let mut centre = 0.0;
+        let mut min_support: Option<(i32, i32)> = None;
+        let mut max_support: Option<(i32, i32)> = None;
+        for &idx in &body.cells {
+            let x = (idx % self.width) as i32;
+            let y = (idx / self.width) as i32;
+            centre += x as f32;
+
+            let supported = match self.index_at(x, y + 1) {
+                Some(below) => {
+                    let below_particle = &self.grid[below];
+                    labels[below] != body.label
+                        && (below_particle.material.is_solid() || below_particle.density >= density)
+                }
+                None => true,
+            };
+            if supported {
+                // Of the outermost supported cells, the lowest is the one the body tips over
+                if min_support.is_none_or(|(min_x, min_y)| x < min_x || (x == min_x && y > min_y)) {
+                    min_support = Some((x, y));
+                }
+                if max_support.is_none_or(|(max_x, max_y)| x > max_x || (x == max_x && y > max_y)) {
+                    max_support = Some((x, y));
+                }
+            }
+        }
+        centre /= body.cells.len() as f32;
+
+        let ((min_x, min_y), (max_x, max_y)) = (min_support?, max_support?);
+        if (max_x as f32) < centre - 0.5 {
+            Some((1, (max_x + 1, max_y + 1)))
+        } else if (min_x as f32) > centre + 0.5 {
+            Some((-1, (min_x, min_y + 1)))
+        } else {
+            None
+        }
+    }
+
+    // Turns the body a quarter turn about the corner of a cell, clockwise when tipping right.
+    // Whatever is in the way takes the cells the body leaves. Returns false if it is blocked
+    fn tip_rigid_body(
+        &mut self,
+        body: &RigidBody,
+        labels: &[usize],
+        dx: i32,
+        pivot: (i32, i32),
+    ) -> bool {
+        let density = self.rigid_body_density(body);
+        let mut moves = Vec::new();
+        for &idx in &body.cells {
+            // Cell centres sit half a cell from the corner, so work in half cells
+            let u = 2 * ((idx % self.width) as i32 - pivot.0) + 1;
+            let v = 2 * ((idx / self.width) as i32 - pivot.1) + 1;
+            let (u, v) = if dx > 0 { (-v, u) } else { (v, -u) };
+            let Some(target) = self.index_at(pivot.0 + (u - 1) / 2, pivot.1 + (v - 1) / 2) else {
+                return false;
+            };
+            if labels[target] != body.label {
+                let particle = &self.grid[target];
+                if particle.material.is_solid() || particle.density >= density {
+                    return false;
+                }
+            }
+            moves.push((idx, target));
+        }
+
+        let mut entered: Vec<usize> = moves
+            .iter()
+            .map(|&(_, to)| to)
+            .filter(|&to| labels[to] != body.label)
+            .collect();
+        let mut left: Vec<usize> = body
+            .cells
+            .iter()
+            .copied()
+            .filter(|idx| !moves.iter().any(|&(_, to)| to == *idx))
+            .collect();
+        entered.sort_unstable();
+        left.sort_unstable();
+        moves.extend(entered.into_iter().zip(left));
+
+        let taken: Vec<(usize, Particle)> = moves
+            .iter()
+            .map(|&(from, to)| (to, std::mem::take(&mut self.grid[from])))
+            .collect();
+        for (to, particle) in taken {
+            self.grid[to] = particle;
+        }
+        true
+    }
+
+    fn rigid_body_density(&self, body: &RigidBody) -> f32 {
+        let total: f32 = body.cells.iter().map(|&idx| self.grid[idx].density).sum();
+        total / body.cells.len() as f32
+    }
+
+    fn move_rigid_body(&mut self, body: &RigidBody, labels: &[usize], dx: i32, dy: i32) {
+        let in_body = |x: i32, y: i32| {
+            self.index_at(x, y)
+                .is_some_and(|idx| labels[idx] == body.label)
+        };
+
+        let mut moves = Vec::new();
+        for &idx in &body.cells {
+            let x = (idx % self.width) as i32;
+            let y = (idx / self.width) as i32;
+            let target = self.index_at(x + dx, y + dy).unwrap();
+            moves.push((idx, target));
+
+            if !in_body(x + dx, y + dy) {
+                // The displaced particle fills the cell left at the back of this run
+                let (mut back_x, mut back_y) = (x, y);
+                while in_body(back_x - dx, back_y - dy) {
+                    back_x -= dx;
+                    back_y -= dy;
+                }
+                moves.push((target, self.index_at(back_x, back_y).unwrap()));
+            }
+        }
+
+        let taken: Vec<(usize, Particle)> = moves
+            .iter()
+            .map(|&(from, to)| (to, std::mem::take(&mut self.grid[from])))
+            .collect();
+        for (to, particle) in taken {
+            self.grid[to] = particle;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Material, Simulation};
+    use rand::prelude::*;
+
+    fn materials(simulation: &Simulation, material: Material) -> Vec<(usize, usize)> {
+        (0..simulation.grid.len())
+            .filter(|&idx| simulation.grid[idx].material == material)
+            .map(|idx| (idx % simulation.width, idx / simulation.width))
+            .collect()
+    }
+
+    #[test]
+    fn unsupported_bodies_fall_as_a_unit() {
+        let mut simulation = Simulation::empty(6, 6);
+        for (x, y) in [(2, 1), (3, 1), (2, 2)] {
+            simulation.put(x, y, Material::Rock);
+        }
+
+        simulation.update_rigid_bodies(&mut StdRng::seed_from_u64(0));
+        assert_eq!(
+            materials(&simulation, Material::Rock),
+            [(2, 2), (3, 2), (2, 3)]
+        );
+    }
+
+    #[test]
+    fn overhanging_bodies_tip_over_the_edge() {
+        // A plank resting on a pillar of sand by one end swings down past the pillar's edge
+        let mut simulation = Simulation::empty(8, 8);
+        for x in 1..=2 {
+            for y in 3..8 {
+                simulation.put(x, y, Material::Sand);
+            }
+        }
+        for x in 1..=5 {
+            simulation.put(x, 2, Material::Wood);
+        }
+
+        simulation.update_rigid_bodies(&mut StdRng::seed_from_u64(0));
+        assert_eq!(
+            materials(&simulation, Material::Wood),
+            [(3, 1), (3, 2), (3, 3), (3, 4), (3, 5)]
+        );
+    }
+
+    #[test]
+    fn landing_pushes_sand_and_water_into_the_cells_left() {
+        let mut simulation = Simulation::empty(6, 6);
+        simulation.put(2, 1, Material::Rock);
+        simulation.put(3, 1, Material::Rock);
+        simulation.put(2, 2, Material::Water);
+        simulation.put(3, 2, Material::Sand);
+
+        // Sinking is slowed by what the body sinks through, so give it a few tries
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            if simulation.grid[2 + 2 * 6].material == Material::Rock {
+                break;
+            }
+            simulation.update_rigid_bodies(&mut rng);
+        }
+        assert_eq!(materials(&simulation, Material::Rock), [(2, 2), (3, 2)]);
+        assert_eq!(materials(&simulation, Material::Water), [(2, 1)]);
+        assert_eq!(materials(&simulation, Material::Sand), [(3, 1)]);
+    }
+}
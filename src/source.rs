@@ -0,0 +1,311 @@
+use crate::{line_points, InsertMode, Material, Simulation};
+use bevy::prelude::*;
+use rand::prelude::*;
+
+// How far emitted particles travel before they are left to the normal rules
+const SOURCE_SPEED: i32 = 8;
+// Ticks on and off for scheduled sources
+const SOURCE_SCHEDULE: (usize, usize) = (50, 50);
+// Particles emitted by sources with a finite quantity
+const SOURCE_QUANTITY: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SourceShape {
+    Point,
+    Circle(i32),
+    Rectangle(i32, i32),
+    // Runs from the source to the given offset
+    Line(i32, i32),
+}
+
+impl SourceShape {
+    pub(crate) fn next(self) -> Self {
+        match self {
+            SourceShape::Point => SourceShape::Circle(3),
+            SourceShape::Circle(_) => SourceShape::Rectangle(8, 3),
+            SourceShape::Rectangle(_, _) => SourceShape::Line(12, 0),
+            SourceShape::Line(_, _) => SourceShape::Point,
+        }
+    }
+
+    // The cells covered relative to the source position
+    pub(crate) fn offsets(&self) -> Vec<(i32, i32)> {
+        match *self {
+            SourceShape::Point => vec![(0, 0)],
+            SourceShape::Circle(radius) => {
+                let mut offsets = Vec::new();
+                for dy in -radius..=radius {
+                    for dx in -radius..=radius {
+                        if dx * dx + dy * dy <= radius * radius {
+                            offsets.push((dx, dy));
+                        }
+                    }
+                }
+                offsets
+            }
+            SourceShape::Rectangle(width, height) => {
+                let mut offsets = Vec::new();
+                for dy in -height / 2..height - height / 2 {
+                    for dx in -width / 2..width - width / 2 {
+                        offsets.push((dx, dy));
+                    }
+                }
+                offsets
+            }
+            SourceShape::Line(dx, dy) => line_points(0, 0, dx, dy),
+        }
+    }
+}
+
+pub(crate) fn next_direction(velocity: IVec2) -> IVec2 {
+    match (velocity.x.signum(), velocity.y.signum()) {
+        (0, 0) => IVec2::new(-SOURCE_SPEED, 0),
+        (-1, 0) => IVec2::new(SOURCE_SPEED, 0),
+        (1, 0) => IVec2::new(0, -SOURCE_SPEED),
+        (0, -1) => IVec2::new(0, SOURCE_SPEED),
+        _ => IVec2::ZERO,
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Source {
+    // Weighted mixture of the materials emitted
+    pub(crate) materials: Vec<(Material, u32)>,
+    pub(crate) rate: usize,
+    pub(crate) last_inserted: usize,
+    pub(crate) shape: SourceShape,
+    pub(crate) velocity: IVec2,
+    // Ticks on followed by ticks off, or always on when None
+    pub(crate) schedule: Option<(usize, usize)>,
+    // Particles left to emit before the source is removed, or unlimited when None
+    pub(crate) remaining: Option<usize>,
+    // Only emit into Air rather than overwriting what is there
+    pub(crate) fill_empty: bool,
+    pub(crate) age: usize,
+}
+
+impl Source {
+    pub(crate) fn new(material: Material, rate: usize) -> Self {
+        Source {
+            materials: vec![(material, 1)],
+            rate,
+            last_inserted: 0,
+            shape: SourceShape::Point,
+            velocity: IVec2::ZERO,
+            schedule: None,
+            remaining: None,
+            fill_empty: false,
+            age: 0,
+        }
+    }
+
+    // Turns the shape and direction a quarter turn clockwise, to match a rotated stamp
+    pub(crate) fn rotate(&mut self) {
+        self.velocity = IVec2::new(-self.velocity.y, self.velocity.x);
+        self.shape = match self.shape {
+            SourceShape::Rectangle(width, height) => SourceShape::Rectangle(height, width),
+            SourceShape::Line(dx, dy) => SourceShape::Line(-dy, dx),
+            shape => shape,
+        };
+    }
+
+    // Flips the shape and direction left to right, to match a mirrored stamp
+    pub(crate) fn mirror(&mut self) {
+        self.velocity.x = -self.velocity.x;
+        if let SourceShape::Line(dx, dy) = self.shape {
+            self.shape = SourceShape::Line(-dx, dy);
+        }
+    }
+
+    fn is_on(&self) -> bool {
+        match self.schedule {
+            Some((on, off)) => self.age % (on + off) < on,
+            None => true,
+        }
+    }
+
+    pub(crate) fn mixture(&self) -> String {
+        let materials: Vec<String> = self
+            .materials
+            .iter()
+            .map(|(material, weight)| format!("{material:?} x{weight}"))
+            .collect();
+        materials.join(" ")
+    }
+
+    fn choose_material(&self, rng: &mut StdRng) -> Material {
+        let total: u32 = self.materials.iter().map(|(_, weight)| weight).sum();
+        let mut choice = rng.gen_range(0..total.max(1));
+        for (material, weight) in &self.materials {
+            if choice < *weight {
+                return *material;
+            }
+            choice -= weight;
+        }
+        self.materials[0].0
+    }
+}
+
+impl Simulation {
+    pub(crate) fn cycle_source_shape(&mut self) {
+        self.source_template.shape = self.source_template.shape.next();
+    }
+
+    pub(crate) fn cycle_source_direction(&mut self) {
+        self.source_template.velocity = next_direction(self.source_template.velocity);
+    }
+
+    pub(crate) fn toggle_source_fill_empty(&mut self) {
+        self.source_template.fill_empty = !self.source_template.fill_empty;
+    }
+
+    pub(crate) fn toggle_source_schedule(&mut self) {
+        self.source_template.schedule = match self.source_template.schedule {
+            Some(_) => None,
+            None => Some(SOURCE_SCHEDULE),
+        };
+    }
+
+    pub(crate) fn toggle_source_quantity(&mut self) {
+        self.source_template.remaining = match self.source_template.remaining {
+            Some(_) => None,
+            None => Some(SOURCE_QUANTITY),
+        };
+    }
+
+    // Adds another share of a material to the mixture new sources emit
+    pub(crate) fn mix_source_material(&mut self, material: Material) {
+        let materials = &mut self.source_template.materials;
+        match materials.iter_mut().find(|(m, _)| *m == material) {
+            Some((_, weight)) => *weight += 1,
+            None => materials.push((material, 1)),
+        }
+        self.insert_mode = InsertMode::Source;
+    }
+
+    pub(crate) fn select_source_at_cursor(&mut self) {
+        self.selected_source = self
+            .cursor
+            .and_then(|(x, y)| self.index_at(x, y))
+            .filter(|idx| self.sources.contains_key(idx));
+    }
+
+    pub(crate) fn delete_selected_source(&mut self) {
+        if let Some(idx) = self.selected_source.take() {
+            self.begin_edit();
+            self.record_source(idx);
+            self.sources.remove(&idx);
+            self.end_edit();
+        }
+    }
+
+    pub(crate) fn move_selected_source(&mut self, dx: i32, dy: i32) {
+        if let Some(idx) = self.selected_source {
+            let x = (idx % self.width) as i32 + dx;
+            let y = (idx / self.width) as i32 + dy;
+            if let Some(new_idx) = self.index_at(x, y) {
+                if !self.sources.contains_key(&new_idx) {
+                    self.begin_edit();
+                    self.record_source(idx);
+                    self.record_source(new_idx);
+                    let source = self.sources.remove(&idx).unwrap();
+                    self.sources.insert(new_idx, source);
+                    self.selected_source = Some(new_idx);
+                    self.end_edit();
+                }
+            }
+        }
+    }
+
+    pub(crate) fn change_selected_source_rate(&mut self, delta: i32) {
+        if let Some(source) = self
+            .selected_source
+            .and_then(|idx| self.sources.get_mut(&idx))
+        {
+            source.rate = (source.rate as i32 + delta).max(1) as usize;
+        }
+    }
+
+    pub(crate) fn toggle_sources_paused(&mut self) {
+        self.sources_paused = !self.sources_paused;
+    }
+
+    pub(crate) fn describe_source(&self, idx: usize) -> Option<String> {
+        let source = self.sources.get(&idx)?;
+        let mut description = format!(
+            "Source ({}, {}): {}, rate {}, last inserted {}, {:?}",
+            idx % self.width,
+            idx / self.width,
+            source.mixture(),
+            source.rate,
+            source.last_inserted,
+            source.shape,
+        );
+        if let Some(remaining) = source.remaining {
+            description += &format!(", {remaining} left");
+        }
+        if self.selected_source == Some(idx) {
+            description += " [selected]";
+        }
+        if self.sources_paused {
+            description += " [paused]";
+        }
+        Some(description)
+    }
+
+    pub(crate) fn update_sources(&mut self, rng: &mut StdRng) {
+        if self.sources_paused {
+            return;
+        }
+
+        let mut emitting = Vec::new();
+        for (idx, source) in &mut self.sources {
+            source.age += 1;
+            if !source.is_on() {
+                continue;
+            }
+            if source.last_inserted <= 1 {
+                source.last_inserted = source.rate;
+                emitting.push(*idx);
+            } else {
+                source.last_inserted -= 1;
+            }
+        }
+
+        for idx in emitting {
+            self.emit(idx, rng);
+        }
+        self.sources.retain(|_, source| source.remaining != Some(0));
+        if self
+            .selected_source
+            .is_some_and(|idx| !self.sources.contains_key(&idx))
+        {
+            self.selected_source = None;
+        }
+    }
+
+    fn emit(&mut self, idx: usize, rng: &mut StdRng) {
+        let x = (idx % self.width) as i32;
+        let y = (idx / self.width) as i32;
+        let offsets = self.sources[&idx].shape.offsets();
+        for (dx, dy) in offsets {
+            let Some(cell) = self.index_at(x + dx, y + dy) else {
+                continue;
+            };
+            let source = self.sources.get_mut(&idx).unwrap();
+            if source.remaining == Some(0) {
+                break;
+            }
+            if source.fill_empty && self.grid[cell].material != Material::Air {
+                continue;
+            }
+
+            let material = source.choose_material(rng);
+            if let Some(remaining) = &mut source.remaining {
+                *remaining -= 1;
+            }
+            self.grid[cell].set_material(material, &self.properties);
+            self.grid[cell].velocity = source.velocity;
+        }
+    }
+}
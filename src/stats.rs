@@ -0,0 +1,166 @@
+use crate::{Material, Simulation};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use strum::IntoEnumIterator;
+
+// Ticks of history kept for the graph
+pub(crate) const HISTORY_LENGTH: usize = 500;
+pub(crate) const STATS_FILE: &str = "stats.csv";
+
+#[derive(Clone)]
+pub(crate) struct TickStats {
+    pub(crate) tick: usize,
+    // Indexed by material
+    pub(crate) counts: Vec<usize>,
+    // Energy left in everything that can still burn
+    pub(crate) fuel: usize,
+    pub(crate) fires: usize,
+    pub(crate) swaps: usize,
+    pub(crate) sources: usize,
+}
+
+#[derive(Default)]
+pub(crate) struct Statistics {
+    pub(crate) history: VecDeque<TickStats>,
+    // Swaps made so far in the tick in progress
+    pub(crate) swaps: usize,
+    csv: Option<BufWriter<File>>,
+    // Why recording last failed, shown in the statistics window
+    pub(crate) error: Option<String>,
+}
+
+impl Statistics {
+    pub(crate) fn latest(&self) -> Option<&TickStats> {
+        self.history.back()
+    }
+
+    pub(crate) fn recording(&self) -> bool {
+        self.csv.is_some()
+    }
+
+    pub(crate) fn start_recording(&mut self) {
+        self.error = None;
+        let file = match File::create(STATS_FILE) {
+            Ok(file) => file,
+            Err(error) => {
+                self.error = Some(format!("Unable to create {STATS_FILE}: {error}"));
+                return;
+            }
+        };
+        let mut csv = BufWriter::new(file);
+        let materials: Vec<String> = Material::iter()
+            .map(|material| format!("{material:?}"))
+            .collect();
+        let header = format!("tick,{},fuel,fires,swaps,sources", materials.join(","));
+        match writeln!(csv, "{header}") {
+            Ok(()) => self.csv = Some(csv),
+            Err(error) => self.error = Some(format!("Unable to write {STATS_FILE}: {error}")),
+        }
+    }
+
+    pub(crate) fn stop_recording(&mut self) {
+        if let Some(mut csv) = self.csv.take() {
+            if let Err(error) = csv.flush() {
+                self.error = Some(format!("Unable to write {STATS_FILE}: {error}"));
+            }
+        }
+    }
+
+    fn write_csv(&mut self, stats: &TickStats) {
+        let Some(csv) = &mut self.csv else {
+            return;
+        };
+        let counts: Vec<String> = stats.counts.iter().map(|count| count.to_string()).collect();
+        let row = format!(
+            "{},{},{},{},{},{}",
+            stats.tick,
+            counts.join(","),
+            stats.fuel,
+            stats.fires,
+            stats.swaps,
+            stats.sources,
+        );
+        if let Err(error) = writeln!(csv, "{row}") {
+            self.error = Some(format!("Unable to write {STATS_FILE}: {error}"));
+            self.csv = None;
+        }
+    }
+}
+
+impl Simulation {
+    // Totals up the world at the end of a tick
+    pub(crate) fn record_statistics(&mut self) {
+        let mut counts = vec![0; Material::iter().count()];
+        let mut fuel = 0;
+        for particle in &self.grid {
+            counts[particle.material as usize] += 1;
+            if particle.material != Material::Fire {
+                fuel += particle.energy;
+            }
+        }
+        let stats = TickStats {
+            tick: self.tick,
+            fires: counts[Material::Fire as usize],
+            counts,
+            fuel,
+            swaps: std::mem::take(&mut self.statistics.swaps),
+            sources: self.sources.len(),
+        };
+
+        self.statistics.write_csv(&stats);
+        self.statistics.history.push_back(stats);
+        if self.statistics.history.len() > HISTORY_LENGTH {
+            self.statistics.history.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HISTORY_LENGTH;
+    use crate::source::Source;
+    use crate::{Material, Simulation};
+    use std::collections::HashMap;
+
+    #[test]
+    fn ticks_total_up_the_grid() {
+        let mut simulation = Simulation::empty(3, 2);
+        simulation.put(0, 0, Material::Wood);
+        simulation.put(1, 0, Material::Oil);
+        simulation.put(2, 0, Material::Fire);
+        simulation.put(0, 1, Material::Fire);
+        simulation
+            .sources
+            .insert(4, Source::new(Material::Water, 1));
+        // Oil sinks into the Air below
+        assert!(simulation.try_swap(1, 1, 1, &mut HashMap::new(), 1));
+
+        simulation.record_statistics();
+        let latest = simulation.statistics.latest().unwrap();
+        assert_eq!(latest.counts[Material::Air as usize], 2);
+        assert_eq!(latest.counts[Material::Wood as usize], 1);
+        assert_eq!(latest.counts[Material::Oil as usize], 1);
+        assert_eq!(latest.fires, 2);
+        let energy = |material| simulation.properties.get(material).energy;
+        assert_eq!(latest.fuel, energy(Material::Wood) + energy(Material::Oil));
+        assert_eq!(latest.swaps, 1);
+        assert_eq!(latest.sources, 1);
+
+        // Swaps are counted afresh each tick
+        simulation.record_statistics();
+        assert_eq!(simulation.statistics.latest().unwrap().swaps, 0);
+    }
+
+    #[test]
+    fn history_stops_growing_at_its_length() {
+        let mut simulation = Simulation::empty(2, 2);
+        for tick in 0..HISTORY_LENGTH + 10 {
+            simulation.tick = tick;
+            simulation.record_statistics();
+        }
+        let history = &simulation.statistics.history;
+        assert_eq!(history.len(), HISTORY_LENGTH);
+        assert_eq!(history.front().unwrap().tick, 10);
+    }
+}
@@ -0,0 +1,97 @@
+use crate::{Material, Simulation};
+use rand::prelude::*;
+use std::collections::VecDeque;
+
+// Chance per tick of an over-extended cell giving way, so collapses spread rather than vanish
+const CRUMBLE_CHANCE: f64 = 0.1;
+
+impl Simulation {
+    pub(crate) fn update_support(&mut self, rng: &mut StdRng) {
+        let spans = self.find_spans();
+        for (idx, span) in spans.into_iter().enumerate() {
+            let particle = &mut self.grid[idx];
+            if !particle.material.is_solid() {
+                continue;
+            }
+
+            particle.supported = span.is_some();
+            if let Some(span) = span {
+                if span > particle.strength && rng.gen_bool(CRUMBLE_CHANCE) {
+                    particle.set_material(Material::Sand, &self.properties);
+                }
+            }
+        }
+    }
+
+    // For every solid, the number of cells it is cantilevered out from something holding it
+    // up, or None if no chain of solids connects it to the ground or a wall
+    fn find_spans(&self) -> Vec<Option<usize>> {
+        let mut spans = vec![None; self.grid.len()];
+        let mut queue = VecDeque::new();
+        for (idx, particle) in self.grid.iter().enumerate() {
+            if particle.material.is_solid() && self.against_wall(idx) {
+                spans[idx] = Some(0);
+                queue.push_back(idx);
+            }
+        }
+
+        // Support passes straight up a column for free, every other step adds to the span
+        while let Some(idx) = queue.pop_front() {
+            let span = spans[idx].unwrap();
+            let x = (idx % self.width) as i32;
+            let y = (idx / self.width) as i32;
+            for (nx, ny, cost) in [(x, y - 1, 0), (x, y + 1, 1), (x - 1, y, 1), (x + 1, y, 1)] {
+                if let Some(n_idx) = self.index_at(nx, ny) {
+                    if !self.grid[n_idx].material.is_solid() {
+                        continue;
+                    }
+                    if spans[n_idx].is_none_or(|s| s > span + cost) {
+                        spans[n_idx] = Some(span + cost);
+                        if cost == 0 {
+                            queue.push_front(n_idx);
+                        } else {
+                            queue.push_back(n_idx);
+                        }
+                    }
+                }
+            }
+        }
+        spans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Material, Simulation};
+    use rand::prelude::*;
+
+    #[test]
+    fn spans_count_sideways_steps_from_the_ground() {
+        let mut simulation = Simulation::empty(7, 5);
+        for (x, y) in [(3, 4), (3, 3), (3, 2), (4, 2), (5, 2), (1, 1)] {
+            simulation.put(x, y, Material::Rock);
+        }
+        let spans = simulation.find_spans();
+        let span = |x: usize, y: usize| spans[y * 7 + x];
+        assert_eq!(span(3, 4), Some(0));
+        // Straight up a column costs nothing
+        assert_eq!(span(3, 2), Some(0));
+        assert_eq!(span(4, 2), Some(1));
+        assert_eq!(span(5, 2), Some(2));
+        // Floating, and not a solid at all
+        assert_eq!(span(1, 1), None);
+        assert_eq!(span(0, 4), None);
+    }
+
+    #[test]
+    fn cells_remember_whether_they_are_held_up() {
+        let mut simulation = Simulation::empty(7, 5);
+        for (x, y) in [(3, 4), (3, 3), (1, 1), (2, 1)] {
+            simulation.put(x, y, Material::Wood);
+        }
+        simulation.update_support(&mut StdRng::seed_from_u64(0));
+        let supported = |x: usize, y: usize| simulation.grid[y * 7 + x].supported;
+        assert!(supported(3, 4) && supported(3, 3));
+        assert!(!supported(1, 1) && !supported(2, 1));
+    }
+}
@@ -0,0 +1,207 @@
+use crate::{line_points, Simulation};
+use bevy::prelude::*;
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Tool {
+    Freehand,
+    Line,
+    Rectangle { filled: bool },
+    Ellipse { filled: bool },
+    // Replaces the connected region of one material under the cursor
+    FloodFill,
+    // Drags out a rectangle to copy, cut or save as a stamp
+    Select,
+    // Places the clipboard
+    Paste,
+}
+
+impl Tool {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Tool::Freehand => "Freehand",
+            Tool::Line => "Line",
+            Tool::Rectangle { filled: false } => "Rectangle",
+            Tool::Rectangle { filled: true } => "Filled rectangle",
+            Tool::Ellipse { filled: false } => "Ellipse",
+            Tool::Ellipse { filled: true } => "Filled ellipse",
+            Tool::FloodFill => "Flood fill",
+            Tool::Select => "Select",
+            Tool::Paste => "Paste",
+        }
+    }
+}
+
+fn rectangle_points(x0: i32, y0: i32, x1: i32, y1: i32, filled: bool) -> Vec<(i32, i32)> {
+    let mut points = Vec::new();
+    for y in y0.min(y1)..=y0.max(y1) {
+        for x in x0.min(x1)..=x0.max(x1) {
+            let on_edge = x == x0 || x == x1 || y == y0 || y == y1;
+            if filled || on_edge {
+                points.push((x, y));
+            }
+        }
+    }
+    points
+}
+
+fn ellipse_points(x0: i32, y0: i32, x1: i32, y1: i32, filled: bool) -> Vec<(i32, i32)> {
+    let centre_x = (x0 + x1) as f32 / 2.0;
+    let centre_y = (y0 + y1) as f32 / 2.0;
+    // Half a cell extra so that thin ellipses still cover their bounding box
+    let radius_x = (x1 - x0).abs() as f32 / 2.0 + 0.5;
+    let radius_y = (y1 - y0).abs() as f32 / 2.0 + 0.5;
+    let inside = |x: i32, y: i32| {
+        let dx = (x as f32 - centre_x) / radius_x;
+        let dy = (y as f32 - centre_y) / radius_y;
+        dx * dx + dy * dy <= 1.0
+    };
+
+    let mut points = Vec::new();
+    for y in y0.min(y1)..=y0.max(y1) {
+        for x in x0.min(x1)..=x0.max(x1) {
+            let on_edge = [(0, -1), (0, 1), (-1, 0), (1, 0)]
+                .iter()
+                .any(|(dx, dy)| !inside(x + dx, y + dy));
+            if inside(x, y) && (filled || on_edge) {
+                points.push((x, y));
+            }
+        }
+    }
+    points
+}
+
+impl Simulation {
+    pub(crate) fn set_tool(&mut self, tool: Tool) {
+        self.tool = tool;
+        self.drag_start = None;
+        self.preview.clear();
+    }
+
+    // Applies the current material, source or sink to every cell given - all edits to the
+    // world made with the mouse go through here
+    pub(crate) fn edit_cells(&mut self, cells: impl IntoIterator<Item = (i32, i32)>) {
+        self.begin_edit();
+        for (x, y) in cells {
+            if let Some(idx) = self.index_at(x, y) {
+                self.insert(idx % self.width, idx / self.width);
+            }
+        }
+        self.end_edit();
+    }
+
+    // The cells the current tool would change if dragged between two points
+    fn tool_points(&self, from: (i32, i32), to: (i32, i32)) -> Vec<(i32, i32)> {
+        match self.tool {
+            Tool::Line => line_points(from.0, from.1, to.0, to.1),
+            Tool::Rectangle { filled } => rectangle_points(from.0, from.1, to.0, to.1, filled),
+            Tool::Ellipse { filled } => ellipse_points(from.0, from.1, to.0, to.1, filled),
+            Tool::Freehand | Tool::FloodFill | Tool::Select | Tool::Paste => vec![],
+        }
+    }
+
+    pub(crate) fn start_drag(&mut self, x: i32, y: i32) {
+        self.drag_start = Some((x, y));
+        self.update_drag(x, y);
+    }
+
+    // Rubber-bands the preview of the shape out to the cursor
+    pub(crate) fn update_drag(&mut self, x: i32, y: i32) {
+        if let Some(from) = self.drag_start {
+            if self.tool == Tool::Select {
+                self.selection = Some((from.0, from.1, x, y));
+                return;
+            }
+
+            let color = if self.erasing {
+                Color::srgba(1.0, 1.0, 1.0, 0.3)
+            } else {
+                self.properties.color(self.material, 1.0)
+            };
+            self.preview = self
+                .tool_points(from, (x, y))
+                .into_iter()
+                .filter_map(|(px, py)| self.index_at(px, py))
+                .map(|idx| (idx, color))
+                .collect();
+        }
+    }
+
+    pub(crate) fn finish_drag(&mut self, x: i32, y: i32) {
+        if self.tool == Tool::Select {
+            self.update_drag(x, y);
+            self.drag_start = None;
+            return;
+        }
+        if let Some(from) = self.drag_start.take() {
+            let points = self.tool_points(from, (x, y));
+            self.edit_cells(points);
+        }
+        self.preview.clear();
+    }
+
+    // Drops the shape being dragged out without drawing it
+    pub(crate) fn cancel_drag(&mut self) {
+        self.drag_start = None;
+        self.preview.clear();
+    }
+
+    pub(crate) fn flood_fill(&mut self, x: i32, y: i32) {
+        let Some(start) = self.index_at(x, y) else {
+            return;
+        };
+        let material = self.grid[start].material;
+
+        let mut region = HashSet::from([start]);
+        let mut stack = vec![start];
+        while let Some(idx) = stack.pop() {
+            let x = (idx % self.width) as i32;
+            let y = (idx / self.width) as i32;
+            for (nx, ny) in [(x, y - 1), (x, y + 1), (x - 1, y), (x + 1, y)] {
+                if let Some(n_idx) = self.index_at(nx, ny) {
+                    if self.grid[n_idx].material == material && region.insert(n_idx) {
+                        stack.push(n_idx);
+                    }
+                }
+            }
+        }
+
+        let cells: Vec<(i32, i32)> = region
+            .into_iter()
+            .map(|idx| ((idx % self.width) as i32, (idx / self.width) as i32))
+            .collect();
+        self.edit_cells(cells);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rectangles_are_hollow_unless_filled() {
+        let hollow = rectangle_points(3, 2, 0, 0, false);
+        assert_eq!(hollow.len(), 10);
+        assert!(!hollow.contains(&(1, 1)));
+        let filled = rectangle_points(0, 0, 3, 2, true);
+        assert_eq!(filled.len(), 12);
+        assert!(filled.contains(&(1, 1)));
+    }
+
+    #[test]
+    fn ellipses_fit_their_bounding_box() {
+        let filled = ellipse_points(0, 0, 6, 4, true);
+        for (x, y) in [(3, 0), (3, 4), (0, 2), (6, 2), (3, 2)] {
+            assert!(filled.contains(&(x, y)), "missing ({x}, {y})");
+        }
+        // Corners are outside
+        assert!(!filled.contains(&(0, 0)));
+        assert!(filled
+            .iter()
+            .all(|&(x, y)| (0..=6).contains(&x) && (0..=4).contains(&y)));
+
+        let hollow = ellipse_points(0, 0, 6, 4, false);
+        assert!(!hollow.contains(&(3, 2)));
+        assert!(hollow.iter().all(|point| filled.contains(point)));
+    }
+}
@@ -0,0 +1,654 @@
+use crate::bindings::KeyBindings;
+use crate::boundary::{Boundary, Edge};
+use crate::brush::{BrushShape, MAX_BRUSH_RADIUS};
+use crate::fill::MAX_BLOB_SIZE;
+use crate::generate::{Generator, MAX_HILLS, MAX_HILL_WIDTH, MIN_HILL_WIDTH};
+use crate::properties::{Properties, PROPERTIES_FILE};
+use crate::stats::{TickStats, HISTORY_LENGTH, STATS_FILE};
+use crate::tools::Tool;
+use crate::{InsertMode, Material, Simulation};
+use bevy::prelude::*;
+use bevy_inspector_egui::bevy_egui::EguiContexts;
+use bevy_inspector_egui::egui;
+use std::collections::VecDeque;
+use strum::IntoEnumIterator;
+
+const PANEL_WIDTH: f32 = 180.0;
+const GRAPH_WIDTH: f32 = 300.0;
+const GRAPH_HEIGHT: f32 = 120.0;
+// The mouse controls are fixed, so they are listed here rather than in keys.conf
+const MOUSE_CONTROLS: [(&str, &str); 8] = [
+    ("paint", "Left-Mouse"),
+    ("erase", "Right-Mouse"),
+    ("paint over Air only", "Ctrl+Left-Mouse"),
+    ("pick material", "Middle-Mouse click, Alt+Left-Mouse"),
+    ("move view", "Middle-Mouse drag"),
+    ("brush size", "Mouse-Wheel"),
+    ("spray density", "Shift+Mouse-Wheel"),
+    ("zoom", "Ctrl+Mouse-Wheel"),
+];
+
+pub(crate) fn to_color32(color: Color) -> egui::Color32 {
+    let color = color.to_srgba();
+    egui::Color32::from_rgb(
+        (color.red * 255.0) as u8,
+        (color.green * 255.0) as u8,
+        (color.blue * 255.0) as u8,
+    )
+}
+
+// A button filled with the material's colour, with its name in whichever of black or white
+// stands out against it
+fn material_swatch(
+    material: Material,
+    selected: bool,
+    properties: &Properties,
+) -> egui::Button<'static> {
+    let color = to_color32(properties.color(material, 1.0));
+    let brightness = color.r() as u32 + color.g() as u32 + color.b() as u32;
+    let text_color = if brightness > 384 {
+        egui::Color32::BLACK
+    } else {
+        egui::Color32::WHITE
+    };
+    egui::Button::new(egui::RichText::new(format!("{material:?}")).color(text_color))
+        .fill(color)
+        .selected(selected)
+}
+
+pub(crate) fn control_panel(mut contexts: EguiContexts, mut simulation: ResMut<Simulation>) {
+    if !simulation.show_panel {
+        return;
+    }
+
+    egui::SidePanel::left("controls")
+        .resizable(false)
+        .exact_width(PANEL_WIDTH)
+        .show(contexts.ctx_mut(), |ui| {
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                ui.heading("Materials");
+                ui.horizontal_wrapped(|ui| {
+                    for material in Material::iter() {
+                        let selected = simulation.material == material;
+                        if ui
+                            .add(material_swatch(material, selected, &simulation.properties))
+                            .clicked()
+                        {
+                            let insert_mode = simulation.insert_mode;
+                            simulation.set_material(material, insert_mode);
+                        }
+                    }
+                });
+                ui.horizontal(|ui| {
+                    for (insert_mode, label) in [
+                        (InsertMode::Material, "Material"),
+                        (InsertMode::Source, "Source"),
+                        (InsertMode::Sink, "Sink"),
+                    ] {
+                        if ui
+                            .selectable_label(simulation.insert_mode == insert_mode, label)
+                            .clicked()
+                        {
+                            simulation.insert_mode = insert_mode;
+                        }
+                    }
+                });
+                let mut rate = 10 - simulation.insert_rate;
+                if ui
+                    .add(egui::Slider::new(&mut rate, 1..=9).text("Insert rate"))
+                    .changed()
+                {
+                    simulation.set_insert_rate(rate);
+                }
+
+                ui.separator();
+                ui.heading("Brush");
+                ui.add(
+                    egui::Slider::new(&mut simulation.brush.radius, 0..=MAX_BRUSH_RADIUS)
+                        .text("Radius"),
+                );
+                ui.horizontal(|ui| {
+                    for shape in [BrushShape::Circle, BrushShape::Square, BrushShape::Spray] {
+                        let label = format!("{shape:?}");
+                        if ui
+                            .selectable_label(simulation.brush.shape == shape, label)
+                            .clicked()
+                        {
+                            simulation.brush.shape = shape;
+                        }
+                    }
+                });
+                if simulation.brush.shape == BrushShape::Spray {
+                    ui.add(
+                        egui::Slider::new(&mut simulation.brush.density, 0.01..=1.0)
+                            .text("Density"),
+                    );
+                }
+                ui.horizontal_wrapped(|ui| {
+                    for tool in [
+                        Tool::Freehand,
+                        Tool::Line,
+                        Tool::Rectangle { filled: false },
+                        Tool::Rectangle { filled: true },
+                        Tool::Ellipse { filled: false },
+                        Tool::Ellipse { filled: true },
+                        Tool::FloodFill,
+                        Tool::Select,
+                    ] {
+                        if ui
+                            .selectable_label(simulation.tool == tool, tool.name())
+                            .clicked()
+                        {
+                            simulation.set_tool(tool);
+                        }
+                    }
+                });
+
+                ui.separator();
+                ui.heading("New sources");
+                ui.horizontal(|ui| {
+                    if ui
+                        .button(format!("{:?}", simulation.source_template.shape))
+                        .clicked()
+                    {
+                        simulation.cycle_source_shape();
+                    }
+                    if ui
+                        .button(format!("Throw {}", simulation.source_template.velocity))
+                        .clicked()
+                    {
+                        simulation.cycle_source_direction();
+                    }
+                });
+                let template = &simulation.source_template;
+                ui.label(format!("Emits {}", template.mixture()));
+                let mut fill_empty = template.fill_empty;
+                let mut scheduled = template.schedule.is_some();
+                let mut limited = template.remaining.is_some();
+                if ui.checkbox(&mut fill_empty, "Only fill Air").changed() {
+                    simulation.toggle_source_fill_empty();
+                }
+                if ui.checkbox(&mut scheduled, "Switch on and off").changed() {
+                    simulation.toggle_source_schedule();
+                }
+                if ui.checkbox(&mut limited, "Run out").changed() {
+                    simulation.toggle_source_quantity();
+                }
+
+                ui.separator();
+                ui.heading("Simulation");
+                ui.horizontal(|ui| {
+                    let label = if simulation.paused { "Resume" } else { "Pause" };
+                    if ui.button(label).clicked() {
+                        simulation.toggle_paused();
+                    }
+                    if ui
+                        .add_enabled(simulation.paused, egui::Button::new("Step"))
+                        .clicked()
+                    {
+                        simulation.step();
+                    }
+                });
+                let label = if simulation.sources_paused {
+                    "Resume sources"
+                } else {
+                    "Pause sources"
+                };
+                if ui.button(label).clicked() {
+                    simulation.toggle_sources_paused();
+                }
+                ui.horizontal(|ui| {
+                    ui.label("Pixel size");
+                    if ui.button("-").clicked() {
+                        simulation.decrease_pixel_size();
+                    }
+                    ui.label(simulation.pixel_size.to_string());
+                    if ui.button("+").clicked() {
+                        simulation.increase_pixel_size();
+                    }
+                });
+                ui.checkbox(&mut simulation.show_properties, "Tune materials");
+                ui.checkbox(&mut simulation.show_statistics, "Statistics");
+                ui.checkbox(&mut simulation.show_help, "Key bindings");
+                ui.horizontal(|ui| {
+                    ui.label(format!("Zoom {:.1}x", simulation.view.zoom));
+                    if ui.button("Reset view").clicked() {
+                        simulation.reset_view();
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Window resizing");
+                    if ui.button(format!("{:?}", simulation.resize_mode)).clicked() {
+                        simulation.cycle_resize_mode();
+                    }
+                });
+                ui.checkbox(&mut simulation.fill_with_air, "Fill new space with Air");
+                ui.horizontal(|ui| {
+                    ui.label("Drop images as");
+                    if ui.button(format!("{:?}", simulation.import_mode)).clicked() {
+                        simulation.cycle_import_mode();
+                    }
+                });
+                let mut show_materials = simulation.show_materials;
+                if ui
+                    .checkbox(&mut show_materials, "Material colours")
+                    .changed()
+                {
+                    simulation.toggle_show_materials();
+                }
+
+                ui.separator();
+                ui.heading("World");
+                ui.horizontal_wrapped(|ui| {
+                    if ui.button("Randomise").clicked() {
+                        simulation.reset_random();
+                    }
+                    if ui
+                        .button(format!("Fill with {:?}", simulation.material))
+                        .clicked()
+                    {
+                        simulation.set_all();
+                    }
+                    if ui.button("Fill with mixture").clicked() {
+                        simulation.show_fill = true;
+                    }
+                    if ui.button("Flip").clicked() {
+                        simulation.flip();
+                    }
+                    if ui.button("Next edge preset").clicked() {
+                        simulation.cycle_boundaries();
+                    }
+                    if ui.button("Clear sources").clicked() {
+                        simulation.clear_sources();
+                    }
+                    if ui.button("Clear sinks").clicked() {
+                        simulation.clear_sinks();
+                    }
+                    if ui.button("Undo").clicked() {
+                        simulation.undo();
+                    }
+                    if ui.button("Redo").clicked() {
+                        simulation.redo();
+                    }
+                });
+
+                egui::CollapsingHeader::new("Edges").show(ui, |ui| {
+                    egui::Grid::new("edges").show(ui, |ui| {
+                        for edge in Edge::iter() {
+                            let boundary = simulation.boundaries.get(edge);
+                            let mut chosen = boundary;
+                            let inflow = match boundary {
+                                Boundary::Inflow(material) => material,
+                                _ => simulation.material,
+                            };
+                            ui.label(format!("{edge:?}"));
+                            egui::ComboBox::from_id_source(("edge", edge as usize))
+                                .selected_text(match boundary {
+                                    Boundary::Inflow(_) => "Inflow".to_string(),
+                                    _ => format!("{boundary:?}"),
+                                })
+                                .show_ui(ui, |ui| {
+                                    for (option, label) in [
+                                        (Boundary::Wall, "Wall"),
+                                        (Boundary::Wrap, "Wrap"),
+                                        (Boundary::Open, "Open"),
+                                        (Boundary::Inflow(inflow), "Inflow"),
+                                    ] {
+                                        ui.selectable_value(&mut chosen, option, label);
+                                    }
+                                });
+                            if let Boundary::Inflow(material) = chosen {
+                                let mut material = material;
+                                egui::ComboBox::from_id_source(("inflow", edge as usize))
+                                    .selected_text(format!("{material:?}"))
+                                    .show_ui(ui, |ui| {
+                                        for option in Material::iter() {
+                                            ui.selectable_value(
+                                                &mut material,
+                                                option,
+                                                format!("{option:?}"),
+                                            );
+                                        }
+                                    });
+                                chosen = Boundary::Inflow(material);
+                            }
+                            if chosen != boundary {
+                                simulation.set_boundary(edge, chosen);
+                            }
+                            ui.end_row();
+                        }
+                    });
+                });
+
+                egui::CollapsingHeader::new("Generate").show(ui, |ui| {
+                    ui.horizontal_wrapped(|ui| {
+                        for generator in Generator::iter() {
+                            let selected = simulation.world.generator == generator;
+                            if ui
+                                .selectable_label(selected, format!("{generator:?}"))
+                                .clicked()
+                            {
+                                simulation.set_generator(generator);
+                            }
+                        }
+                    });
+                    let world = &mut simulation.world;
+                    ui.add_enabled_ui(world.generator != Generator::Noise, |ui| {
+                        ui.add(egui::Slider::new(&mut world.ground, 0.0..=1.0).text("Ground"));
+                        ui.add(egui::Slider::new(&mut world.hills, 0.0..=MAX_HILLS).text("Hills"));
+                        ui.add(
+                            egui::Slider::new(
+                                &mut world.hill_width,
+                                MIN_HILL_WIDTH as f32..=MAX_HILL_WIDTH as f32,
+                            )
+                            .text("Hill width"),
+                        );
+                        ui.add(egui::Slider::new(&mut world.lakes, 0.0..=1.0).text("Lakes"));
+                        ui.add(egui::Slider::new(&mut world.caves, 0.0..=1.0).text("Caves"));
+                        ui.add(egui::Slider::new(&mut world.oil, 0.0..=1.0).text("Oil"));
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Seed");
+                        ui.add(egui::DragValue::new(&mut world.seed));
+                    });
+                    ui.horizontal(|ui| {
+                        if ui.button("Generate").clicked() {
+                            simulation.generate_world();
+                        }
+                        if ui.button("New seed").clicked() {
+                            simulation.reset_random();
+                        }
+                    });
+                });
+
+                // Only looks in the stamps directory while the list is open
+                egui::CollapsingHeader::new("Stamps").show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            egui::TextEdit::singleline(&mut simulation.stamp_name)
+                                .hint_text("Name")
+                                .desired_width(80.0),
+                        );
+                        if ui
+                            .add_enabled(
+                                simulation.selection.is_some(),
+                                egui::Button::new("Save selection"),
+                            )
+                            .clicked()
+                        {
+                            simulation.save_stamp();
+                        }
+                    });
+                    for name in simulation.stamp_names() {
+                        let current = simulation.stamp.as_ref() == Some(&name);
+                        if ui.selectable_label(current, &name).clicked() {
+                            simulation.load_stamp(&name);
+                        }
+                    }
+                });
+            });
+        });
+}
+
+pub(crate) fn material_properties_window(
+    mut contexts: EguiContexts,
+    mut simulation: ResMut<Simulation>,
+) {
+    let mut open = simulation.show_properties;
+    egui::Window::new("Material properties")
+        .open(&mut open)
+        .show(contexts.ctx_mut(), |ui| {
+            egui::Grid::new("properties").striped(true).show(ui, |ui| {
+                ui.label("Material");
+                ui.label("Density");
+                ui.label("Viscosity");
+                ui.label("Energy");
+                ui.label("Strength");
+                ui.label("Colour");
+                ui.end_row();
+
+                for material in Material::iter() {
+                    let mut properties = simulation.properties.get(material);
+                    ui.label(format!("{material:?}"));
+                    let mut changed = ui
+                        .add(
+                            egui::DragValue::new(&mut properties.density)
+                                .speed(0.01)
+                                .range(0.0..=10.0),
+                        )
+                        .changed();
+                    changed |= ui
+                        .add(
+                            egui::DragValue::new(&mut properties.viscosity)
+                                .speed(0.1)
+                                .range(0.0..=20.0),
+                        )
+                        .changed();
+                    changed |= ui
+                        .add(egui::DragValue::new(&mut properties.energy).range(0..=1000))
+                        .changed();
+                    // Only solids hold themselves up
+                    changed |= ui
+                        .add_enabled(
+                            material.is_solid(),
+                            egui::DragValue::new(&mut properties.strength).range(0..=200),
+                        )
+                        .changed();
+                    changed |= ui.color_edit_button_rgb(&mut properties.color).changed();
+                    ui.end_row();
+
+                    if changed {
+                        simulation.set_properties(material, properties);
+                    }
+                }
+            });
+            ui.horizontal(|ui| {
+                if ui.button(format!("Save to {PROPERTIES_FILE}")).clicked() {
+                    simulation.properties.save();
+                }
+                if ui.button("Defaults").clicked() {
+                    simulation.reset_properties();
+                }
+            });
+        });
+    simulation.show_properties = open;
+}
+
+pub(crate) fn fill_window(mut contexts: EguiContexts, mut simulation: ResMut<Simulation>) {
+    let mut open = simulation.show_fill;
+    egui::Window::new("Fill with mixture")
+        .open(&mut open)
+        .show(contexts.ctx_mut(), |ui| {
+            egui::Grid::new("fill").striped(true).show(ui, |ui| {
+                ui.label("Material");
+                ui.label("Weight");
+                ui.label("Share");
+                ui.end_row();
+
+                for idx in 0..simulation.fill.materials.len() {
+                    let material = simulation.fill.materials[idx].0;
+                    ui.add(material_swatch(material, false, &simulation.properties));
+                    ui.add(egui::Slider::new(
+                        &mut simulation.fill.materials[idx].1,
+                        0..=100,
+                    ));
+                    ui.label(format!("{:.0}%", simulation.fill.percentage(material)));
+                    ui.end_row();
+                }
+            });
+            ui.add(
+                egui::Slider::new(&mut simulation.fill.blob_size, 1..=MAX_BLOB_SIZE)
+                    .text("Blob size"),
+            );
+            ui.horizontal(|ui| {
+                if ui.button("Fill world").clicked() {
+                    simulation.fill_all_mixture();
+                }
+                if ui
+                    .add_enabled(
+                        simulation.selection.is_some(),
+                        egui::Button::new("Fill selection"),
+                    )
+                    .clicked()
+                {
+                    simulation.fill_selection_mixture();
+                }
+            });
+        });
+    simulation.show_fill = open;
+}
+
+// Moves the text shown along the left of the window out from under the panel
+pub(crate) fn make_room_for_panel(
+    simulation: Res<Simulation>,
+    mut query: Query<&mut Style, With<Text>>,
+) {
+    let left = if simulation.show_panel {
+        PANEL_WIDTH + 20.0
+    } else {
+        5.0
+    };
+    // Text anchored to the right is left alone
+    for mut style in &mut query {
+        if style.left != Val::Auto {
+            style.left = Val::Px(left);
+        }
+    }
+}
+
+// Air is drawn black, which would vanish against the graph background
+fn graph_color(material: Material, properties: &Properties) -> egui::Color32 {
+    match material {
+        Material::Air => egui::Color32::GRAY,
+        _ => to_color32(properties.color(material, 1.0)),
+    }
+}
+
+// Count of each material over the recent ticks, scrolling left as new ticks arrive
+fn material_graph(ui: &mut egui::Ui, history: &VecDeque<TickStats>, properties: &Properties) {
+    let size = egui::vec2(GRAPH_WIDTH, GRAPH_HEIGHT);
+    let (response, painter) = ui.allocate_painter(size, egui::Sense::hover());
+    let rect = response.rect;
+    painter.rect_filled(rect, 0.0, egui::Color32::from_gray(20));
+
+    let most = history
+        .iter()
+        .flat_map(|stats| stats.counts.iter())
+        .max()
+        .copied()
+        .unwrap_or(0)
+        .max(1);
+    for material in Material::iter() {
+        let points: Vec<egui::Pos2> = history
+            .iter()
+            .enumerate()
+            .map(|(tick, stats)| {
+                let count = stats.counts[material as usize];
+                egui::pos2(
+                    rect.left() + rect.width() * tick as f32 / (HISTORY_LENGTH - 1) as f32,
+                    rect.bottom() - rect.height() * count as f32 / most as f32,
+                )
+            })
+            .collect();
+        painter.add(egui::Shape::line(
+            points,
+            egui::Stroke::new(1.5, graph_color(material, properties)),
+        ));
+    }
+}
+
+pub(crate) fn statistics_window(mut contexts: EguiContexts, mut simulation: ResMut<Simulation>) {
+    let mut open = simulation.show_statistics;
+    egui::Window::new("Statistics")
+        .open(&mut open)
+        .show(contexts.ctx_mut(), |ui| {
+            if let Some(latest) = simulation.statistics.latest() {
+                egui::Grid::new("statistics").show(ui, |ui| {
+                    for material in Material::iter() {
+                        ui.colored_label(
+                            graph_color(material, &simulation.properties),
+                            format!("{material:?}"),
+                        );
+                        ui.label(latest.counts[material as usize].to_string());
+                        ui.end_row();
+                    }
+                    for (label, value) in [
+                        ("Fuel energy", latest.fuel),
+                        ("Fires", latest.fires),
+                        ("Swaps per tick", latest.swaps),
+                        ("Sources", latest.sources),
+                    ] {
+                        ui.label(label);
+                        ui.label(value.to_string());
+                        ui.end_row();
+                    }
+                });
+                material_graph(ui, &simulation.statistics.history, &simulation.properties);
+            }
+
+            if !simulation.outflow.is_empty() {
+                ui.label("Lost through open edges");
+                egui::Grid::new("outflow").show(ui, |ui| {
+                    for material in Material::iter() {
+                        if let Some(count) = simulation.outflow.get(&material) {
+                            ui.colored_label(
+                                graph_color(material, &simulation.properties),
+                                format!("{material:?}"),
+                            );
+                            ui.label(count.to_string());
+                            ui.end_row();
+                        }
+                    }
+                });
+            }
+
+            if simulation.statistics.recording() {
+                if ui
+                    .button(format!("Stop recording to {STATS_FILE}"))
+                    .clicked()
+                {
+                    simulation.statistics.stop_recording();
+                }
+            } else if ui.button(format!("Record to {STATS_FILE}")).clicked() {
+                simulation.statistics.start_recording();
+            }
+            if let Some(error) = &simulation.statistics.error {
+                ui.colored_label(egui::Color32::RED, error);
+            }
+        });
+    simulation.show_statistics = open;
+}
+
+pub(crate) fn help_window(
+    mut contexts: EguiContexts,
+    mut simulation: ResMut<Simulation>,
+    bindings: Res<KeyBindings>,
+) {
+    let mut open = simulation.show_help;
+    egui::Window::new("Key bindings")
+        .open(&mut open)
+        .default_height(400.0)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.label("Change these by putting lines like `undo = Ctrl+z` in keys.conf");
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                egui::Grid::new("bindings").striped(true).show(ui, |ui| {
+                    for (action, keys) in &bindings.bindings {
+                        let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+                        ui.label(action.name());
+                        ui.label(if keys.is_empty() {
+                            "none".to_string()
+                        } else {
+                            keys.join(", ")
+                        });
+                        ui.end_row();
+                    }
+                    for (action, button) in MOUSE_CONTROLS {
+                        ui.label(action);
+                        ui.label(button);
+                        ui.end_row();
+                    }
+                });
+            });
+        });
+    simulation.show_help = open;
+}
@@ -0,0 +1,93 @@
+use crate::Simulation;
+use bevy::prelude::*;
+
+const MAX_ZOOM: f32 = 32.0;
+const ZOOM_STEP: f32 = 1.25;
+// Screen pixels the arrow keys move the view each frame
+const PAN_SPEED: f32 = 8.0;
+
+// The part of the world shown in the window. At a zoom of 1 the whole world fits the window
+pub(crate) struct View {
+    pub(crate) zoom: f32,
+    // World position, in cells, of the top left of the window
+    pub(crate) offset: Vec2,
+    // Where the mouse was last frame while dragging the view around
+    pub(crate) drag_from: Option<Vec2>,
+    // Whether the view has moved since the button went down, so a click can be told from a drag
+    pub(crate) dragged: bool,
+}
+
+impl Default for View {
+    fn default() -> Self {
+        View {
+            zoom: 1.0,
+            offset: Vec2::ZERO,
+            drag_from: None,
+            dragged: false,
+        }
+    }
+}
+
+impl Simulation {
+    // Screen pixels per cell along each axis
+    fn view_scale(&self) -> Vec2 {
+        Vec2::new(
+            self.max_width as f32 / self.width as f32,
+            self.max_height as f32 / self.height as f32,
+        ) * self.view.zoom
+    }
+
+    fn world_at_screen(&self, position: Vec2) -> Vec2 {
+        self.view.offset + position / self.view_scale()
+    }
+
+    // The grid cell drawn at a position in the window
+    pub(crate) fn cell_at_screen(&self, position: Vec2) -> (i32, i32) {
+        let world = self.world_at_screen(position).floor();
+        (world.x as i32, world.y as i32)
+    }
+
+    // Keeps the view from wandering off the edge of the world
+    pub(crate) fn clamp_view(&mut self) {
+        let visible = Vec2::new(self.max_width as f32, self.max_height as f32) / self.view_scale();
+        let world = Vec2::new(self.width as f32, self.height as f32);
+        self.view.offset = self
+            .view
+            .offset
+            .clamp(Vec2::ZERO, (world - visible).max(Vec2::ZERO));
+    }
+
+    // Zooms in or out by a number of steps, keeping the cell under the mouse where it is
+    pub(crate) fn zoom_view(&mut self, steps: f32, position: Vec2) {
+        let before = self.world_at_screen(position);
+        self.view.zoom = (self.view.zoom * ZOOM_STEP.powf(steps)).clamp(1.0, MAX_ZOOM);
+        self.view.offset = before - position / self.view_scale();
+        self.clamp_view();
+    }
+
+    // Moves the view by a distance in screen pixels
+    pub(crate) fn pan_view(&mut self, delta: Vec2) {
+        self.view.offset += delta / self.view_scale();
+        self.clamp_view();
+    }
+
+    pub(crate) fn pan_view_with_keys(&mut self, direction: Vec2) {
+        self.pan_view(direction * PAN_SPEED);
+    }
+
+    pub(crate) fn drag_view(&mut self, position: Option<Vec2>) {
+        match (self.view.drag_from, position) {
+            (Some(from), Some(to)) if from != to => {
+                self.pan_view(from - to);
+                self.view.dragged = true;
+            }
+            (None, Some(_)) => self.view.dragged = false,
+            _ => {}
+        }
+        self.view.drag_from = position;
+    }
+
+    pub(crate) fn reset_view(&mut self) {
+        self.view = View::default();
+    }
+}